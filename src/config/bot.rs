@@ -1,12 +1,23 @@
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use serenity::model::Permissions;
 use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::ops::Deref;
 
+use crate::handler::Handler;
 use crate::instance::Instance;
 
-#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[derive(Clone, Default, Serialize, Deserialize)]
 #[serde(rename_all(deserialize = "kebab-case"))]
 pub struct Config {
     pub bot_token: String,
+    // seconds all instances get to stop when the bot shuts down
+    #[serde(default = "Config::default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    // where paused schedules are remembered across restarts
+    #[serde(default = "Config::default_schedules_path")]
+    pub schedules_path: String,
     #[serde(flatten)]
     pub instances: HashMap<String, Instance>,
 }
@@ -16,6 +27,163 @@ pub struct Config {
 pub struct StartupConfig {
     pub time_to_wait: u64,
     pub wait_for_stdout: bool,
+    // the instance is ready once a line matches, replaces the time-to-wait guess
+    #[serde(default)]
+    pub ready_pattern: Option<Patterns>,
+    // matching lines abort the startup and mark the instance as failed
+    #[serde(default)]
+    pub failure_patterns: Vec<ConfigRegex>,
+    // seconds until an instance that isn't ready is marked as failed and killed
+    #[serde(default)]
+    pub startup_timeout: Option<u64>,
+}
+
+/// A single regex or a list of them, any of them has to match.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Patterns {
+    One(ConfigRegex),
+    Many(Vec<ConfigRegex>),
+}
+
+impl Patterns {
+    pub fn as_slice(&self) -> &[ConfigRegex] {
+        match self {
+            Patterns::One(pattern) => std::slice::from_ref(pattern),
+            Patterns::Many(patterns) => patterns,
+        }
+    }
+}
+
+/// How a child is stopped. Every step is only taken if the child didn't exit after the
+/// previous one: write `stdin-cmd`, wait `time-to-wait`, send `signal`, wait
+/// `kill-timeout` and kill the process group.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, rename_all(deserialize = "kebab-case"))]
+pub struct ShutdownConfig {
+    pub stdin_cmd: Option<String>,
+    pub time_to_wait: u64,
+    pub signal: String,
+    pub kill_timeout: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    #[default]
+    Never,
+    OnFailure,
+    Always,
+}
+
+/// How lines of the child's stderr are treated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StderrMode {
+    /// kept as its own stream
+    #[default]
+    Separate,
+    /// handled as if the child wrote them to stdout
+    Merge,
+    /// kept as its own stream and logged as warnings
+    Warn,
+}
+
+/// When a child that exited on its own gets started again. Restarts back off
+/// exponentially from `backoff` up to `max-backoff` seconds, after `max-retries`
+/// restarts within `window` seconds the instance is given up.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, rename_all(deserialize = "kebab-case"))]
+pub struct RestartPolicyConfig {
+    pub policy: RestartPolicy,
+    pub max_retries: u32,
+    pub backoff: u64,
+    pub max_backoff: u64,
+    pub window: u64,
+}
+
+/// Probes the instance every `interval` seconds. After `failure-threshold` failed probes
+/// in a row it is unhealthy, after `success-threshold` passed ones it is healthy again.
+/// Without a `ready-pattern` the first healthy result also marks the instance as ready.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct HealthCheckConfig {
+    pub probe: HealthProbe,
+    #[serde(default = "HealthCheckConfig::default_interval")]
+    pub interval: u64,
+    #[serde(default = "HealthCheckConfig::default_timeout")]
+    pub timeout: u64,
+    #[serde(default = "HealthCheckConfig::default_failure_threshold")]
+    pub failure_threshold: u32,
+    #[serde(default = "HealthCheckConfig::default_success_threshold")]
+    pub success_threshold: u32,
+    // restarts the instance once it becomes unhealthy
+    #[serde(default)]
+    pub restart: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum HealthProbe {
+    /// passes if a connection can be opened
+    Tcp { address: String },
+    /// passes if a GET returns the expected status
+    #[serde(rename_all(deserialize = "kebab-case"))]
+    Http {
+        url: String,
+        #[serde(default = "HealthProbe::default_expected_status")]
+        expected_status: u16,
+    },
+    /// passes if the command, run like the instance, exits with the expected code
+    #[serde(rename_all(deserialize = "kebab-case"))]
+    Exec {
+        cmd_path: String,
+        #[serde(default)]
+        cmd_args: Vec<String>,
+        #[serde(default)]
+        expected_code: i32,
+    },
+}
+
+/// Declares a ready instance as hung if it stays silent for `timeout` seconds. With a
+/// `heartbeat-pattern` only matching lines count, `probe-cmd` is written to stdin every
+/// `probe-interval` seconds to provoke them.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct WatchdogConfig {
+    pub timeout: u64,
+    #[serde(default)]
+    pub probe_cmd: Option<String>,
+    #[serde(default = "WatchdogConfig::default_probe_interval")]
+    pub probe_interval: u64,
+    #[serde(default)]
+    pub heartbeat_pattern: Option<ConfigRegex>,
+    #[serde(default)]
+    pub recovery: WatchdogRecovery,
+}
+
+/// What happens to a hung instance, the channel is always alerted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WatchdogRecovery {
+    Alert,
+    // stops it with the shutdown sequence and starts it again
+    #[default]
+    Restart,
+    // kills it, the restart policy decides what happens next
+    Kill,
+}
+
+/// Streams the output of every run into its own thread of the instance channel,
+/// batched every `interval` seconds. `include` and `exclude` are regexes, a line is
+/// streamed if it matches any include pattern (or there are none) and no exclude pattern.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, rename_all(deserialize = "kebab-case"))]
+pub struct LogStreamConfig {
+    pub interval: u64,
+    pub include: Vec<ConfigRegex>,
+    pub exclude: Vec<ConfigRegex>,
+    pub strip_ansi: bool,
 }
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
@@ -25,12 +193,169 @@ pub struct RestrictionConfig {
     pub fallback_channel_id: u64,
     pub allowed_channel_ids: Option<Vec<u64>>,
     pub allowed_user_ids: Option<Vec<u64>>,
+    pub allowed_role_ids: Option<Vec<u64>>,
+    pub required_permissions: Option<Vec<ConfigPermission>>,
+}
+
+/// A discord permission, parsed while the config is loaded, an unknown name keeps the
+/// bot from starting. Accepts the names discord shows (`Manage Server`) as well as the
+/// api names (`MANAGE_GUILD`), case-insensitive.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ConfigPermission(Permissions);
+
+impl ConfigPermission {
+    /// The names discord shows where they differ from the ones serenity knows.
+    const DISCORD_NAMES: [(&'static str, Permissions); 11] = [
+        ("Create Invite", Permissions::CREATE_INSTANT_INVITE),
+        ("Manage Server", Permissions::MANAGE_GUILD),
+        ("View Server Insights", Permissions::VIEW_GUILD_INSIGHTS),
+        ("Video", Permissions::STREAM),
+        ("View Channels", Permissions::VIEW_CHANNEL),
+        (
+            "Send Text-to-Speech Messages",
+            Permissions::SEND_TTS_MESSAGES,
+        ),
+        ("Use External Emoji", Permissions::USE_EXTERNAL_EMOJIS),
+        (
+            "Manage Expressions",
+            Permissions::MANAGE_EMOJIS_AND_STICKERS,
+        ),
+        ("Use Application Commands", Permissions::USE_SLASH_COMMANDS),
+        ("Use Activities", Permissions::USE_EMBEDDED_ACTIVITIES),
+        ("Timeout Members", Permissions::MODERATE_MEMBERS),
+    ];
+}
+
+impl TryFrom<String> for ConfigPermission {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        let normalize = |name: &str| {
+            name.chars()
+                .filter(|c| c.is_alphanumeric())
+                .collect::<String>()
+                .to_lowercase()
+        };
+        let normalized = normalize(&name);
+
+        let discord_name = ConfigPermission::DISCORD_NAMES
+            .iter()
+            .find(|(discord_name, _)| normalize(discord_name) == normalized)
+            .map(|(_, permission)| *permission);
+        discord_name
+            .or_else(|| {
+                (0..u64::BITS)
+                    .filter_map(|bit| Permissions::from_bits(1 << bit))
+                    .find(|permission| {
+                        normalize(&format!("{permission:?}")) == normalized
+                            || permission
+                                .get_permission_names()
+                                .first()
+                                .is_some_and(|display_name| normalize(display_name) == normalized)
+                    })
+            })
+            .map(ConfigPermission)
+            .ok_or_else(|| format!("unknown permission `{name}`"))
+    }
+}
+
+impl From<ConfigPermission> for String {
+    fn from(permission: ConfigPermission) -> Self {
+        format!("{:?}", permission.0)
+    }
+}
+
+impl Deref for ConfigPermission {
+    type Target = Permissions;
+
+    fn deref(&self) -> &Permissions {
+        &self.0
+    }
 }
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "kebab-case"))]
 pub struct SlashCommandConfig {
     pub description: String,
     pub stdin: Option<StdinConfig>,
+    // runs a program instead, only used without stdin
+    pub exec: Option<ExecConfig>,
+    // runs these steps instead, only used without stdin and exec
+    pub workflow: Option<Vec<WorkflowStep>>,
+    // overrides for the instance restrictions, only for this command
+    pub allowed_user_ids: Option<Vec<u64>>,
+    pub allowed_role_ids: Option<Vec<u64>>,
+    pub required_permissions: Option<Vec<ConfigPermission>>,
+    // rendered into the stdin templates and exec args as `{name}`
+    #[serde(default)]
+    pub options: Vec<CommandOptionConfig>,
+}
+
+/// An option of a slash command. `choices`, `min`/`max` (integer) and `min-length`/
+/// `max-length` (string) are enforced by discord and checked again on use, `regex`
+/// (string) is compiled when the config is loaded and checked on use.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct CommandOptionConfig {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: CommandOptionKind,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default = "CommandOptionConfig::default_required")]
+    pub required: bool,
+    #[serde(default)]
+    pub choices: Vec<ChoiceValue>,
+    pub regex: Option<ConfigRegex>,
+    pub min: Option<i64>,
+    pub max: Option<i64>,
+    pub min_length: Option<u16>,
+    pub max_length: Option<u16>,
+}
+
+/// A regex compiled while the config is loaded, an invalid one keeps the bot from starting.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ConfigRegex(Regex);
+
+impl TryFrom<String> for ConfigRegex {
+    type Error = regex::Error;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        Regex::new(&pattern).map(ConfigRegex)
+    }
+}
+
+impl From<ConfigRegex> for String {
+    fn from(regex: ConfigRegex) -> Self {
+        regex.0.as_str().to_string()
+    }
+}
+
+impl Deref for ConfigRegex {
+    type Target = Regex;
+
+    fn deref(&self) -> &Regex {
+        &self.0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CommandOptionKind {
+    String,
+    Integer,
+    Boolean,
+    // rendered as the user name
+    User,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ChoiceValue {
+    Integer(i32),
+    String(String),
 }
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
@@ -39,10 +364,290 @@ pub struct StdinConfig {
     pub cmd: String,
     // todo: make optional and impl default response
     pub interaction_msg: String,
+    // answer with the output following cmd instead of just interaction_msg
+    pub capture: Option<CaptureConfig>,
+}
+
+/// A program run in the exec dir and environment of the instance, whether it is running
+/// or not. Runs of the same command on an instance wait for each other.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct ExecConfig {
+    pub cmd_path: String,
+    #[serde(default)]
+    pub cmd_args: Vec<String>,
+    // seconds until the program is killed
+    #[serde(default = "ExecConfig::default_timeout")]
+    pub timeout: u64,
+    // lines of output shown in the reply once it exited
+    #[serde(default = "ExecConfig::default_tail_lines")]
+    pub tail_lines: usize,
+}
+
+/// Runs the command `action` of the instance whenever `cron` matches, in `timezone` or
+/// the local one. Built-in commands don't have to be listed in the slash commands.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct ScheduleConfig {
+    pub cron: String,
+    pub timezone: Option<String>,
+    pub action: String,
+    // values of the options of the command
+    #[serde(default)]
+    pub options: HashMap<String, serenity::json::Value>,
+}
+
+/// A step of a workflow, failing once its `timeout` (in seconds) passed. A failed step
+/// runs its `on-failure` steps and ends the workflow.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct WorkflowStep {
+    #[serde(flatten)]
+    pub action: WorkflowAction,
+    #[serde(default = "WorkflowStep::default_timeout")]
+    pub timeout: u64,
+    #[serde(default)]
+    pub on_failure: Vec<WorkflowStep>,
+}
+
+/// What a step of a workflow does. Steps without an `instance` act on the instance
+/// the command was run for.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum WorkflowAction {
+    /// writes to stdin, options are filled in
+    Stdin { cmd: String },
+    /// waits for a line matching the regex, written since the last stdin step
+    WaitFor { pattern: ConfigRegex },
+    /// waits without ever timing out
+    Sleep { seconds: u64 },
+    /// runs a program like an exec command, fails on a non-zero exit
+    #[serde(rename_all(deserialize = "kebab-case"))]
+    Exec {
+        cmd_path: String,
+        #[serde(default)]
+        cmd_args: Vec<String>,
+    },
+    /// starts an instance and waits until it is running
+    Start { instance: Option<String> },
+    /// stops an instance and waits until it exited
+    Stop { instance: Option<String> },
+    /// waits until an instance exited, e.g. after a stdin `stop`
+    WaitForExit { instance: Option<String> },
+    /// posts to the channel of the command, options are filled in
+    Message { text: String },
+}
+
+/// Collects the output following a stdin cmd until `until` matches, `lines` lines were
+/// collected or `timeout` seconds passed, whichever comes first. A line matching
+/// `error-pattern` ends the collection too and marks the reply as failed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct CaptureConfig {
+    pub until: Option<ConfigRegex>,
+    pub lines: Option<usize>,
+    #[serde(default = "CaptureConfig::default_timeout")]
+    pub timeout: u64,
+    pub error_pattern: Option<ConfigRegex>,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            stdin_cmd: None,
+            time_to_wait: 30,
+            signal: String::from("SIGTERM"),
+            kill_timeout: 10,
+        }
+    }
+}
+
+impl Default for RestartPolicyConfig {
+    fn default() -> Self {
+        RestartPolicyConfig {
+            policy: RestartPolicy::Never,
+            max_retries: 5,
+            backoff: 5,
+            max_backoff: 300,
+            window: 600,
+        }
+    }
+}
+
+impl Default for LogStreamConfig {
+    fn default() -> Self {
+        LogStreamConfig {
+            interval: 5,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            strip_ansi: true,
+        }
+    }
+}
+
+impl WorkflowStep {
+    fn default_timeout() -> u64 {
+        300
+    }
+
+    /// Whether the step or one of its `on-failure` steps is a `wait-for`.
+    fn waits_for_output(&self) -> bool {
+        matches!(self.action, WorkflowAction::WaitFor { .. })
+            || self.on_failure.iter().any(WorkflowStep::waits_for_output)
+    }
+}
+
+impl ExecConfig {
+    fn default_timeout() -> u64 {
+        300
+    }
+
+    fn default_tail_lines() -> usize {
+        20
+    }
+}
+
+impl CaptureConfig {
+    fn default_timeout() -> u64 {
+        5
+    }
+}
+
+impl CommandOptionConfig {
+    fn default_required() -> bool {
+        true
+    }
+}
+
+impl Display for ChoiceValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChoiceValue::Integer(value) => write!(f, "{value}"),
+            ChoiceValue::String(value) => write!(f, "{value}"),
+        }
+    }
+}
+
+impl HealthCheckConfig {
+    fn default_interval() -> u64 {
+        30
+    }
+
+    fn default_timeout() -> u64 {
+        5
+    }
+
+    fn default_failure_threshold() -> u32 {
+        3
+    }
+
+    fn default_success_threshold() -> u32 {
+        1
+    }
+}
+
+impl WatchdogConfig {
+    fn default_probe_interval() -> u64 {
+        60
+    }
+}
+
+impl HealthProbe {
+    fn default_expected_status() -> u16 {
+        200
+    }
+}
+
+// the token is masked, the config gets dumped into the trace log
+impl Debug for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Config")
+            .field("bot_token", &"***")
+            .field("shutdown_timeout", &self.shutdown_timeout)
+            .field("schedules_path", &self.schedules_path)
+            .field("instances", &self.instances)
+            .finish()
+    }
 }
 
 impl Config {
+    fn default_shutdown_timeout() -> u64 {
+        60
+    }
+
+    fn default_schedules_path() -> String {
+        String::from("./schedules.toml")
+    }
+
     pub fn from_path(path: &str) -> Config {
-        confy::load_path::<Config>(path).unwrap()
+        let cfg = confy::load_path::<Config>(path).unwrap();
+        if let Err(err) = cfg.validate() {
+            panic!("Invalid config `{path}`: {err}");
+        }
+        cfg
+    }
+
+    /// Catches what deserializing can't, combinations of keys that would only fail at runtime.
+    fn validate(&self) -> Result<(), String> {
+        for (instance_name, instance) in &self.instances {
+            for (slash_cmd_name, slash_cmd) in &instance.slash_commands {
+                let captures = slash_cmd
+                    .stdin
+                    .as_ref()
+                    .is_some_and(|stdin| stdin.capture.is_some());
+                if captures && instance.log_lines == 0 {
+                    return Err(format!(
+                        "`/{slash_cmd_name}` of `{instance_name}` captures output, which needs log-lines above 0"
+                    ));
+                }
+                let waits_for_output = slash_cmd
+                    .workflow
+                    .as_ref()
+                    .is_some_and(|steps| steps.iter().any(WorkflowStep::waits_for_output));
+                if waits_for_output && instance.log_lines == 0 {
+                    return Err(format!(
+                        "`/{slash_cmd_name}` of `{instance_name}` waits for output, which needs log-lines above 0"
+                    ));
+                }
+            }
+        }
+        Handler::check_schedules(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn permission(name: &str) -> Result<Permissions, String> {
+        ConfigPermission::try_from(name.to_string()).map(|permission| *permission)
+    }
+
+    #[test]
+    fn permission_accepts_discord_names() {
+        assert_eq!(permission("Manage Server"), Ok(Permissions::MANAGE_GUILD));
+        assert_eq!(
+            permission("timeout members"),
+            Ok(Permissions::MODERATE_MEMBERS)
+        );
+        assert_eq!(permission("Kick Members"), Ok(Permissions::KICK_MEMBERS));
+    }
+
+    #[test]
+    fn permission_accepts_api_names() {
+        assert_eq!(permission("MANAGE_GUILD"), Ok(Permissions::MANAGE_GUILD));
+        assert_eq!(permission("ban_members"), Ok(Permissions::BAN_MEMBERS));
+    }
+
+    #[test]
+    fn permission_rejects_unknown_names() {
+        assert!(permission("Manage Everything").is_err());
+        assert!(permission("").is_err());
+    }
+
+    #[test]
+    fn permission_round_trips() {
+        let name = String::from(ConfigPermission(Permissions::MANAGE_GUILD));
+        assert_eq!(permission(&name), Ok(Permissions::MANAGE_GUILD));
     }
 }
@@ -1,14 +1,44 @@
 mod async_trait;
+mod capture;
+mod commands;
+mod crash_report;
+mod exec;
+mod log_stream;
+mod logs;
+mod options;
+mod outbox;
+mod permissions;
+mod registration;
+mod schedule;
+mod state;
+mod workflow;
 
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
 use serenity::http::Http;
+use serenity::model::application::interaction::application_command::CommandDataOption;
 use serenity::model::prelude::ChannelId;
 use serenity::prelude::*;
 use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::{timeout_at, Instant};
 
-use crate::config::bot;
+use self::commands::format_duration;
+use self::log_stream::LogStream;
+use self::outbox::{Outbox, PendingMessage};
+use self::permissions::Caller;
+use self::schedule::{PausedSchedules, Schedule};
+use self::state::{InstanceState, InstanceStatus};
+use self::workflow::RunningWorkflow;
+use crate::config::bot::{self, WatchdogRecovery};
+use crate::instance::crash::CrashOutcome;
+use crate::instance::logs::{LogBuffer, SharedLogBuffer};
 use crate::instance::{InstanceInEvents, InstanceOutEvents};
 
 pub enum HandlerEvents {
@@ -19,86 +49,197 @@ pub enum HandlerEvents {
 pub struct ActiveInstance {
     pub sender: Sender<InstanceInEvents>,
     pub channel: ChannelId,
+    pub pid: Option<u32>,
+    // the stream of the current run, if the instance streams its output
+    pub log_stream: Option<LogStream>,
 }
 
 pub struct Handler {
     pub cfg: bot::Config,
     http: Http,
     pub active_instances: Arc<Mutex<HashMap<String, ActiveInstance>>>,
+    // lock before `active_instances` when both are needed
+    pub states: Arc<Mutex<HashMap<String, InstanceStatus>>>,
+    // sent after every state transition, wakes up workflows waiting for a state
+    state_changed: watch::Sender<()>,
     pub sender: Sender<HandlerEvents>,
+    accepting_commands: AtomicBool,
+    outbox: Outbox,
+    // kept beyond a run, so the output of a crashed instance can still be read
+    logs: HashMap<String, SharedLogBuffer>,
+    // held while an exec command runs, keyed by instance and command name
+    exec_locks: HashMap<(String, String), Arc<Mutex<()>>>,
+    workflows: std::sync::Mutex<HashMap<String, RunningWorkflow>>,
+    schedules: Vec<Arc<Schedule>>,
+    // persisted to `schedules-path`
+    paused_schedules: std::sync::Mutex<PausedSchedules>,
 }
 
 impl Handler {
-    const CMD_NAME_SEPARATOR: &'static str = "-";
+    pub const INSTANCE_OPTION: &'static str = "instance";
+    const MAX_AUTOCOMPLETE_CHOICES: usize = 25;
 
     pub fn new(cfg: bot::Config) -> Arc<Handler> {
         let http = Http::new(&cfg.bot_token);
         let (sender, receiver) = mpsc::channel::<HandlerEvents>(5);
+        let logs = cfg
+            .instances
+            .iter()
+            .map(|(instance_name, instance)| {
+                (instance_name.clone(), LogBuffer::shared(instance.log_lines))
+            })
+            .collect();
+        let exec_locks = cfg
+            .instances
+            .iter()
+            .flat_map(|(instance_name, instance)| {
+                instance
+                    .slash_commands
+                    .iter()
+                    .filter(|(_, slash_cmd)| slash_cmd.exec.is_some())
+                    .map(|(slash_cmd_name, _)| {
+                        (
+                            (instance_name.clone(), slash_cmd_name.clone()),
+                            Arc::new(Mutex::new(())),
+                        )
+                    })
+            })
+            .collect();
+        let schedules =
+            schedule::schedules(&cfg).expect("schedules are checked when the config is loaded");
+        let paused_schedules = PausedSchedules::load(&cfg.schedules_path);
         let handler = Arc::new(Handler {
             cfg,
             http,
             active_instances: Arc::new(Mutex::new(HashMap::new())),
+            states: Arc::new(Mutex::new(HashMap::new())),
+            state_changed: watch::channel(()).0,
             sender,
+            accepting_commands: AtomicBool::new(true),
+            outbox: Outbox::default(),
+            logs,
+            exec_locks,
+            workflows: std::sync::Mutex::new(HashMap::new()),
+            schedules,
+            paused_schedules: std::sync::Mutex::new(paused_schedules),
         });
 
         tokio::spawn(Self::start_receiver_thread(handler.clone(), receiver));
+        tokio::spawn(Self::start_outbox_thread(handler.clone()));
+        for schedule in &handler.schedules {
+            tokio::spawn(Self::start_schedule_thread(
+                handler.clone(),
+                schedule.clone(),
+            ));
+        }
 
         handler
     }
 
-    pub fn make_cmd_name(instance_name: &String, slash_cmd_name: &String) -> String {
-        format!(
-            "{}{}{}",
-            slash_cmd_name,
-            Handler::CMD_NAME_SEPARATOR,
-            instance_name
-        )
+    /// Reads the instance a command targets from its options.
+    pub fn instance_option(options: &[CommandDataOption]) -> Option<&str> {
+        options
+            .iter()
+            .find(|option| option.name == Handler::INSTANCE_OPTION)
+            .and_then(|option| option.value.as_ref())
+            .and_then(|value| value.as_str())
     }
 
-    pub fn separat_cmd_name(cmd_name: &str) -> Result<(&str, &str), String> {
-        let splitted: Vec<&str> = cmd_name.split(Handler::CMD_NAME_SEPARATOR).collect();
+    /// Names of the instances offering the given command to the caller,
+    /// filtered by the partial input of the autocompletion.
+    pub fn instances_for(&self, slash_cmd_name: &str, caller: &Caller, partial: &str) -> Vec<&str> {
+        let partial = partial.to_lowercase();
+        let mut instance_names: Vec<&str> = self
+            .cfg
+            .instances
+            .iter()
+            .filter(|(instance_name, _)| instance_name.to_lowercase().contains(&partial))
+            .filter(|(_, instance)| {
+                instance
+                    .slash_commands
+                    .get(slash_cmd_name)
+                    .is_some_and(|slash_cmd| {
+                        permissions::check(instance, slash_cmd, caller).is_ok()
+                    })
+            })
+            .map(|(instance_name, _)| instance_name.as_str())
+            .collect();
 
-        if splitted.len() < 2 {
-            return Err(format!(
-                "Split on cmd name with separator ({}) resulted in less then 2.",
-                Handler::CMD_NAME_SEPARATOR
-            ));
+        instance_names.sort_unstable();
+        instance_names.truncate(Handler::MAX_AUTOCOMPLETE_CHOICES);
+        instance_names
+    }
+
+    pub fn is_accepting_commands(&self) -> bool {
+        self.accepting_commands.load(Ordering::SeqCst)
+    }
+
+    /// Stops accepting commands, cancels the running workflows and runs the shutdown
+    /// sequence of every active instance in parallel. Process groups that are still
+    /// alive after the deadline get killed.
+    ///
+    /// The deadline starts right away, the notices in discord are posted while the
+    /// instances are already stopping.
+    pub async fn shutdown(handler: Arc<Self>, deadline: Duration) {
+        let deadline_at = Instant::now() + deadline;
+        handler.accepting_commands.store(false, Ordering::SeqCst);
+        // workflows and schedules can't start instances from now on
+        handler.cancel_workflows();
+
+        let active: Vec<(String, Sender<InstanceInEvents>)> = {
+            // waits for starts that are already past their check
+            let _states = handler.states.lock().await;
+            handler
+                .active_instances
+                .lock()
+                .await
+                .iter()
+                .map(|(instance_name, active_instance)| {
+                    (instance_name.clone(), active_instance.sender.clone())
+                })
+                .collect()
+        };
+        log::info!("Shutting down, stopping {} instance(s)", active.len());
+
+        let mut stopping = JoinSet::new();
+        for (instance_name, sender) in active {
+            handler
+                .set_state(&instance_name, InstanceState::Stopping)
+                .await;
+
+            let handler = handler.clone();
+            stopping.spawn(async move {
+                if sender.send(InstanceInEvents::Stop).await.is_err() {
+                    return;
+                }
+                let notice = Self::send_discord_message_to_instance_channel(
+                    &handler,
+                    &instance_name,
+                    format!("macobot is shutting down, stopping `{instance_name}`."),
+                );
+                // the runner drops its receiver once the child exited
+                tokio::join!(notice, sender.closed());
+            });
         }
 
-        let mut err: Option<String> = None;
-        let mut name: Option<&str> = None;
-        let mut instance: Option<&str> = None;
-
-        for i in 0..1 {
-            match splitted.get(i) {
-                Some(item) => {
-                    if i == 0 {
-                        name = Some(item);
-                    } else {
-                        instance = Some(item);
+        if timeout_at(deadline_at, async {
+            while stopping.join_next().await.is_some() {}
+        })
+        .await
+        .is_err()
+        {
+            for (instance_name, active_instance) in handler.active_instances.lock().await.iter() {
+                log::error!(
+                    "[{instance_name}] Didn't stop within {}s, killing it",
+                    deadline.as_secs()
+                );
+                if let Some(pid) = active_instance.pid {
+                    if let Err(err) = killpg(Pid::from_raw(pid as i32), Signal::SIGKILL) {
+                        log::error!("[{instance_name}] Couldn't kill process group {pid}: {err}");
                     }
                 }
-                None => {
-                    err = if let Some(err) = err {
-                        Some(format!("Couldn't retrieve {i}. {err}"))
-                    } else {
-                        Some(format!("Couldn't retrieve {i}."))
-                    };
-                }
             }
         }
-
-        if let Some(why) = err {
-            Err(why)
-        } else if let Some(name) = name {
-            if let Some(instance) = instance {
-                Ok((name, instance))
-            } else {
-                Err(String::from("Unexpected error during cmd name separation"))
-            }
-        } else {
-            Err(String::from("Unexpected error during cmd name separation"))
-        }
     }
 
     pub async fn start_receiver_thread(handler: Arc<Self>, receiver: Receiver<HandlerEvents>) {
@@ -108,11 +249,40 @@ impl Handler {
         loop {
             match receiver.recv().await {
                 Some(HandlerEvents::ErrorOnSendingDiscordMessage(error_msg)) => {
-                    todo!("HandlerEvents::ErrorOnSendingDiscordMessage: {error_msg}")
+                    log::error!("Couldn't send discord message, queued it for a retry: {error_msg}")
                 }
                 Some(HandlerEvents::InstanceOutEvent(instance_event_out)) => {
                     match instance_event_out {
-                        InstanceOutEvents::Stopped(instance_name) => {
+                        InstanceOutEvents::Started(instance_name, pid, log_position) => {
+                            log::debug!("[{instance_name}] Child spawned with pid {pid}.");
+                            handler
+                                .set_state(&instance_name, InstanceState::Starting)
+                                .await;
+                            if let Some(active_instance) = handler
+                                .active_instances
+                                .lock()
+                                .await
+                                .get_mut(&instance_name)
+                            {
+                                active_instance.pid = Some(pid);
+                                // replaces the stream of a previous run
+                                active_instance.log_stream = handler
+                                    .cfg
+                                    .instances
+                                    .get(&instance_name)
+                                    .and_then(|instance| instance.log_stream.clone())
+                                    .and_then(|cfg| {
+                                        Self::start_log_stream(
+                                            handler.clone(),
+                                            instance_name.clone(),
+                                            active_instance.channel,
+                                            cfg,
+                                            log_position,
+                                        )
+                                    });
+                            }
+                        }
+                        InstanceOutEvents::Stopped(instance_name, status) => {
                             log::debug!(
                                 "[{instance_name}] Stopping finished. Sending stopped message."
                             );
@@ -122,64 +292,393 @@ impl Handler {
                                 format!("Stopped `{instance_name}`"),
                             )
                             .await;
+                            handler
+                                .instance_exited(
+                                    &instance_name,
+                                    InstanceState::Stopped,
+                                    Some(status),
+                                )
+                                .await;
+                        }
+                        InstanceOutEvents::StoppedWithError(instance_name, err) => {
+                            log::warn!("[{instance_name}] Stopped with error: {err}");
+                            Self::send_discord_message_to_instance_channel(
+                                &handler,
+                                &instance_name,
+                                format!("`{instance_name}` stopped unexpectedly: {err}"),
+                            )
+                            .await;
+                            handler
+                                .instance_exited(&instance_name, InstanceState::Crashed, Some(err))
+                                .await;
+                        }
+                        InstanceOutEvents::Crashed(instance_name, report) => {
+                            log::warn!("[{instance_name}] Crashed with {}", report.exit);
+                            let (exit, outcome) = (report.exit.clone(), report.outcome);
+                            // sent before the runner is forgotten, to reach its channel
+                            handler.send_crash_report(&instance_name, report).await;
+                            match outcome {
+                                CrashOutcome::Stopped => {
+                                    handler
+                                        .instance_exited(
+                                            &instance_name,
+                                            InstanceState::Crashed,
+                                            Some(exit),
+                                        )
+                                        .await
+                                }
+                                CrashOutcome::Restarting { .. } => {
+                                    handler.instance_restarting(&instance_name, exit).await
+                                }
+                                CrashOutcome::RestartsExhausted(_) => {
+                                    handler
+                                        .instance_exited(
+                                            &instance_name,
+                                            InstanceState::Failed,
+                                            Some(exit),
+                                        )
+                                        .await
+                                }
+                            }
+                        }
+                        InstanceOutEvents::Restarting(instance_name, status, attempt, delay) => {
+                            log::warn!("[{instance_name}] Exited with {status}, restart {attempt} in {delay:?}");
+                            handler
+                                .instance_restarting(&instance_name, status.clone())
+                                .await;
+                            Self::send_discord_message_to_instance_channel(
+                                &handler,
+                                &instance_name,
+                                format!(
+                                    "`{instance_name}` exited with {status}. Restarting in {} (attempt {attempt}).",
+                                    format_duration(chrono::Duration::seconds(delay.as_secs() as i64))
+                                ),
+                            )
+                            .await;
+                        }
+                        InstanceOutEvents::RestartsExhausted(instance_name, status, attempts) => {
+                            log::error!(
+                                "[{instance_name}] Crash loop, giving up after {attempts} restarts"
+                            );
+                            Self::send_discord_message_to_instance_channel(
+                                &handler,
+                                &instance_name,
+                                format!(
+                                    "`{instance_name}` exited with {status} and was already restarted {attempts} times. Giving up, the instance is marked as failed."
+                                ),
+                            )
+                            .await;
+                            handler
+                                .instance_exited(
+                                    &instance_name,
+                                    InstanceState::Failed,
+                                    Some(status),
+                                )
+                                .await;
                         }
-                        InstanceOutEvents::StoppedWithError(err) => todo!("StoppedError: {err}"),
-                        InstanceOutEvents::ExecuteStdinCommandFailure(err) => {
-                            todo!("ExecuteStdinCommandFailure: {err}")
+                        InstanceOutEvents::ExecuteStdinCommandFailure(instance_name, err) => {
+                            log::error!("[{instance_name}] Couldn't write to stdin: {err}");
+                            Self::send_discord_message_to_instance_channel(
+                                &handler,
+                                &instance_name,
+                                format!("Couldn't send the command to `{instance_name}`: {err}"),
+                            )
+                            .await;
                         }
-                        InstanceOutEvents::StartupTimeoutFinished(instance_name) => {
-                            log::debug!("[{instance_name}] Startup timeout finished. Sending startup message.");
+                        InstanceOutEvents::Ready {
+                            name: instance_name,
+                            after,
+                            matched_line,
+                        } => {
+                            log::debug!(
+                                "[{instance_name}] Ready after {after:?}. Sending startup message."
+                            );
+                            handler
+                                .advance_state(
+                                    &instance_name,
+                                    &[InstanceState::Starting, InstanceState::Unhealthy],
+                                    InstanceState::Running,
+                                )
+                                .await;
+                            let after =
+                                format_duration(chrono::Duration::seconds(after.as_secs() as i64));
+                            let msg = match matched_line {
+                                Some(line) => format!(
+                                    "Started `{instance_name}` after {after}, it reported ready: `{}`",
+                                    line.replace('`', "'")
+                                ),
+                                None => format!("Started `{instance_name}` after {after}. Server/Application is up and running."),
+                            };
                             Self::send_discord_message_to_instance_channel(
                                 &handler,
                                 &instance_name,
-                                format!("Started `{instance_name}`. Server/Application is up and running.")
-                            ).await;
+                                msg,
+                            )
+                            .await;
                         }
-                        InstanceOutEvents::ChangeDirFailure => {
-                            todo!("InstanceOutEvents::ChangeDirFailure")
+                        InstanceOutEvents::StartupFailed(instance_name, reason) => {
+                            log::error!("[{instance_name}] Startup failed: {reason}");
+                            Self::send_discord_message_to_instance_channel(
+                                &handler,
+                                &instance_name,
+                                format!(
+                                    "`{instance_name}` failed to start, it was killed: {reason}"
+                                ),
+                            )
+                            .await;
+                            handler
+                                .instance_exited(
+                                    &instance_name,
+                                    InstanceState::Failed,
+                                    Some(reason),
+                                )
+                                .await;
+                        }
+                        InstanceOutEvents::Unhealthy(instance_name, reason, restarting) => {
+                            handler
+                                .advance_state(
+                                    &instance_name,
+                                    &[InstanceState::Running],
+                                    InstanceState::Unhealthy,
+                                )
+                                .await;
+                            let action = if restarting { " Restarting it." } else { "" };
+                            Self::send_discord_message_to_instance_channel(
+                                &handler,
+                                &instance_name,
+                                format!("`{instance_name}` is unhealthy: {reason}.{action}"),
+                            )
+                            .await;
                         }
-                        InstanceOutEvents::StdoutInitializingFailure => {
-                            todo!("InstanceOutEvents::StdoutInitializingFailure")
+                        InstanceOutEvents::Healthy(instance_name) => {
+                            handler
+                                .advance_state(
+                                    &instance_name,
+                                    &[InstanceState::Starting, InstanceState::Unhealthy],
+                                    InstanceState::Running,
+                                )
+                                .await;
+                            Self::send_discord_message_to_instance_channel(
+                                &handler,
+                                &instance_name,
+                                format!("`{instance_name}` is healthy again."),
+                            )
+                            .await;
+                        }
+                        InstanceOutEvents::Hung(instance_name, reason, recovery) => {
+                            handler
+                                .advance_state(
+                                    &instance_name,
+                                    &[InstanceState::Running],
+                                    InstanceState::Unhealthy,
+                                )
+                                .await;
+                            let action = match recovery {
+                                WatchdogRecovery::Alert => "",
+                                WatchdogRecovery::Restart => " Restarting it.",
+                                WatchdogRecovery::Kill => " Killing it.",
+                            };
+                            Self::send_discord_message_to_instance_channel(
+                                &handler,
+                                &instance_name,
+                                format!("`{instance_name}` seems to hang, {reason}.{action}"),
+                            )
+                            .await;
+                        }
+                        InstanceOutEvents::Responsive(instance_name) => {
+                            handler
+                                .advance_state(
+                                    &instance_name,
+                                    &[InstanceState::Starting, InstanceState::Unhealthy],
+                                    InstanceState::Running,
+                                )
+                                .await;
+                            Self::send_discord_message_to_instance_channel(
+                                &handler,
+                                &instance_name,
+                                format!("`{instance_name}` is responsive again."),
+                            )
+                            .await;
+                        }
+                        InstanceOutEvents::ShutdownStep(instance_name, step) => {
+                            Self::send_discord_message_to_instance_channel(
+                                &handler,
+                                &instance_name,
+                                step,
+                            )
+                            .await;
+                        }
+                        InstanceOutEvents::ExecDirFailure(instance_name, err) => {
+                            log::error!("[{instance_name}] Invalid exec dir: {err}");
+                            Self::send_discord_message_to_instance_channel(
+                                &handler,
+                                &instance_name,
+                                format!("Couldn't start `{instance_name}`, its exec dir is invalid: {err}"),
+                            )
+                            .await;
+                            handler
+                                .instance_exited(&instance_name, InstanceState::Crashed, None)
+                                .await;
+                        }
+                        InstanceOutEvents::SpawnFailure(instance_name, err) => {
+                            log::error!("[{instance_name}] Couldn't spawn child: {err}");
+                            Self::send_discord_message_to_instance_channel(
+                                &handler,
+                                &instance_name,
+                                format!("Couldn't start `{instance_name}`: {err}"),
+                            )
+                            .await;
+                            handler
+                                .instance_exited(&instance_name, InstanceState::Crashed, None)
+                                .await;
+                        }
+                        InstanceOutEvents::StdoutInitializingFailure(instance_name) => {
+                            log::error!("[{instance_name}] Couldn't retrieve stdout of the child");
+                            Self::send_discord_message_to_instance_channel(
+                                &handler,
+                                &instance_name,
+                                format!(
+                                    "Couldn't start `{instance_name}`, its output isn't readable."
+                                ),
+                            )
+                            .await;
+                            handler
+                                .instance_exited(&instance_name, InstanceState::Crashed, None)
+                                .await;
                         }
                     }
                 }
-                None => todo!("None, so channel is closed... handle in next revision"),
+                None => {
+                    log::error!("Handler event channel was closed, stopping receiver thread");
+                    break;
+                }
             };
         }
     }
 
+    pub async fn set_state(&self, instance_name: &str, state: InstanceState) {
+        self.states
+            .lock()
+            .await
+            .entry(instance_name.to_string())
+            .or_default()
+            .transition(instance_name, state);
+        self.state_changed.send_replace(());
+    }
+
+    /// Moves on to `state`, but only from one of the `from` states. The runner might have
+    /// queued the event before a command moved the instance on, e.g. to stopping.
+    async fn advance_state(
+        &self,
+        instance_name: &str,
+        from: &[InstanceState],
+        state: InstanceState,
+    ) {
+        let mut states = self.states.lock().await;
+        let status = states.entry(instance_name.to_string()).or_default();
+        if from.contains(&status.state) {
+            status.transition(instance_name, state);
+            self.state_changed.send_replace(());
+        }
+    }
+
+    /// The sender of the runner, as long as the child can receive commands.
+    pub async fn running_sender(
+        &self,
+        instance_name: &str,
+    ) -> Result<Sender<InstanceInEvents>, String> {
+        let states = self.states.lock().await;
+        states
+            .get(instance_name)
+            .cloned()
+            .unwrap_or_default()
+            .validate_running(instance_name)?;
+
+        match self.active_instances.lock().await.get(instance_name) {
+            Some(active_instance) => Ok(active_instance.sender.clone()),
+            None => Err(format!(
+                "There is no running instance for `{instance_name}`."
+            )),
+        }
+    }
+
+    /// Records the final state of a child and forgets its runner.
+    async fn instance_exited(
+        &self,
+        instance_name: &str,
+        state: InstanceState,
+        last_exit: Option<String>,
+    ) {
+        let mut states = self.states.lock().await;
+        let status = states.entry(instance_name.to_string()).or_default();
+        status.transition(instance_name, state);
+        self.state_changed.send_replace(());
+        if last_exit.is_some() {
+            status.last_exit = last_exit;
+        }
+        self.active_instances.lock().await.remove(instance_name);
+    }
+
+    /// Records that a child exited and waits for its restart.
+    async fn instance_restarting(&self, instance_name: &str, last_exit: String) {
+        {
+            let mut states = self.states.lock().await;
+            let status = states.entry(instance_name.to_string()).or_default();
+            status.transition(instance_name, InstanceState::Restarting);
+            self.state_changed.send_replace(());
+            status.last_exit = Some(last_exit);
+        }
+        if let Some(active_instance) = self.active_instances.lock().await.get_mut(instance_name) {
+            active_instance.log_stream = None;
+        }
+    }
+
     async fn send_discord_message_to_instance_channel(
         handler: &Handler,
         instance_name: &str,
         msg: String,
     ) {
-        let channel =
-            if let Some(instance) = handler.active_instances.lock().await.get(instance_name) {
-                Some(instance.channel)
-            } else if let Some(instance) = handler.cfg.instances.get(instance_name) {
-                Some(ChannelId(instance.restrictions.fallback_channel_id))
-            } else {
-                log::error!("Couldn't retrieve any active channel for `{instance_name}`.");
-                None
-            };
-
-        if let Some(channel) = channel {
+        if let Some(channel) = handler.instance_channel(instance_name).await {
             handler.send_discord_message(channel, msg).await;
         }
     }
 
+    /// The channel the instance was started from, or its fallback channel.
+    async fn instance_channel(&self, instance_name: &str) -> Option<ChannelId> {
+        if let Some(instance) = self.active_instances.lock().await.get(instance_name) {
+            Some(instance.channel)
+        } else if let Some(instance) = self.cfg.instances.get(instance_name) {
+            Some(ChannelId(instance.restrictions.fallback_channel_id))
+        } else {
+            log::error!("Couldn't retrieve any active channel for `{instance_name}`.");
+            None
+        }
+    }
+
+    /// Sends a message or queues it for a retry, if discord can't be reached. Messages
+    /// that can't be delivered at all, e.g. to an unknown channel, are dropped.
+    ///
+    /// Messages are queued right away while older ones are waiting, to keep their order.
     async fn send_discord_message(&self, channel: ChannelId, msg: String) {
-        let res = channel.send_message(&self.http, |m| m.content(msg)).await;
+        if !self.outbox.is_empty().await {
+            self.outbox.push(PendingMessage { channel, msg }).await;
+            return;
+        }
+
+        let res = channel.send_message(&self.http, |m| m.content(&msg)).await;
         match res {
             Ok(result) => log::trace!("{:#?}", result),
+            Err(err) if !outbox::is_transient(&err) => log::error!(
+                "Dropping message for channel {channel}, it can't be delivered: {err}. Message: {msg}"
+            ),
             Err(err) => {
-                if let Err(send_err) = self
-                    .sender
-                    .send(HandlerEvents::ErrorOnSendingDiscordMessage(format!(
-                        "{:?}",
-                        err
-                    )))
-                    .await
+                self.outbox.push(PendingMessage { channel, msg }).await;
+                // never block on our own channel, the receiver thread might be the caller
+                if let Err(send_err) =
+                    self.sender
+                        .try_send(HandlerEvents::ErrorOnSendingDiscordMessage(format!(
+                            "channel {channel}: {err:?}"
+                        )))
                 {
                     log::error!("Error occurred during sending HandlerEvent: {}", send_err)
                 }
@@ -1,93 +1,179 @@
 use serenity::{
     async_trait,
     model::{
-        application::interaction::{Interaction, InteractionResponseType},
+        application::interaction::{
+            application_command::ApplicationCommandInteraction, Interaction,
+            InteractionResponseType,
+        },
+        channel::AttachmentType,
         gateway::Ready,
-        id::GuildId,
     },
     prelude::*,
 };
 
-use super::{ActiveInstance, Handler};
-use crate::instance::{InstanceInEvents, InstanceRunner};
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc;
+
+use super::commands::CommandResponse;
+use super::permissions::{self, Caller};
+use super::registration;
+use super::Handler;
 
 #[async_trait]
 impl EventHandler for Handler {
     async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
         if let Interaction::ApplicationCommand(command) = interaction {
             log::trace!("Received command interaction: {:#?}", command);
+            let received = Instant::now();
 
-            let cmd_name = command.data.name.as_str();
+            let slash_cmd_name = command.data.name.as_str();
 
-            let command_response = match Handler::separat_cmd_name(cmd_name) {
-                Ok((slash_cmd_name, instance_name)) => {
+            let mut ephemeral = false;
+            let mut deferred = false;
+            let command_response = match Handler::instance_option(&command.data.options) {
+                _ if !self.is_accepting_commands() => {
+                    ephemeral = true;
+                    String::from("macobot is shutting down and doesn't accept commands anymore.")
+                        .into()
+                }
+                Some(instance_name) => {
                     if let Some(instance) = self.cfg.instances.get(instance_name) {
                         if let Some(slash_cmd) = instance.slash_commands.get(slash_cmd_name) {
-                            match slash_cmd_name.trim() {
-                                "start" => {
-                                    log::debug!("Start command received for [{instance_name}]");
-                                    self.active_instances.lock().await.insert(
-                                        instance_name.to_string(),
-                                        ActiveInstance {
-                                            sender: InstanceRunner::new(
-                                                instance_name.to_string(),
-                                                instance.clone(),
-                                                self.sender.clone(),
-                                            ),
-                                            channel: command.channel_id,
-                                        },
-                                    );
-                                    format!("Starting `{instance_name}`. Will send a message after startup.")
+                            if let Err(denied) = permissions::check(
+                                instance,
+                                slash_cmd,
+                                &Caller::from_interaction(&command),
+                            ) {
+                                log::info!(
+                                    "[{instance_name}] Rejected `{slash_cmd_name}`: {denied}"
+                                );
+                                ephemeral = true;
+                                denied.to_string().into()
+                            } else {
+                                if Handler::is_deferred(slash_cmd_name, slash_cmd) {
+                                    if let Err(why) = command.defer(&ctx.http).await {
+                                        log::warn!("Cannot defer slash command: {}", why);
+                                        return;
+                                    }
+                                    deferred = true;
                                 }
-                                _ => {
-                                    // impl for own custom commands
-                                    if let Some(stdin) = slash_cmd.stdin.clone() {
-                                        let await_instances = self.active_instances.lock().await;
-
-                                        if let Some(active_instance) =
-                                            await_instances.get(&instance_name.to_string())
-                                        {
-                                            let sender_result = active_instance
-                                                .sender
-                                                .send(InstanceInEvents::ExecuteStdinCommand(
-                                                    stdin.cmd,
-                                                ))
-                                                .await;
-
-                                            if let Err(err) = sender_result {
-                                                err.to_string()
-                                            } else {
-                                                stdin.interaction_msg.replace("{}", &instance_name)
-                                            }
-                                        } else {
-                                            format!("There is no running instance for `{instance_name}`.")
-                                        }
-                                    } else {
-                                        String::from("not currently supported or implemented (5)")
+                                let (progress, updates) = mpsc::channel(1);
+                                let options = &command.data.options;
+                                let channel_id = command.channel_id;
+                                // owns the sender, so the progress ends with the command
+                                let run = async move {
+                                    self.run_command(
+                                        instance_name,
+                                        instance,
+                                        slash_cmd_name,
+                                        slash_cmd,
+                                        options,
+                                        channel_id,
+                                        &progress,
+                                    )
+                                    .await
+                                };
+                                let response = if deferred {
+                                    let (response, ()) = tokio::join!(
+                                        run,
+                                        Handler::show_progress(&ctx, &command, received, updates)
+                                    );
+                                    response
+                                } else {
+                                    drop(updates);
+                                    run.await
+                                };
+                                match response {
+                                    Ok(response) => response,
+                                    Err(why) => {
+                                        ephemeral = true;
+                                        why.into()
                                     }
                                 }
                             }
                         } else {
-                            String::from("not currently supported or implemented (4)")
+                            ephemeral = true;
+                            format!("`{instance_name}` has no `/{slash_cmd_name}` command.").into()
                         }
                     } else {
-                        String::from("not currently supported or implemented (3)")
+                        ephemeral = true;
+                        format!("There is no instance named `{instance_name}`.").into()
                     }
                 }
-                Err(why) => why,
+                None => {
+                    ephemeral = true;
+                    format!(
+                        "Missing the `{}` option for `/{slash_cmd_name}`.",
+                        Handler::INSTANCE_OPTION
+                    )
+                    .into()
+                }
             };
 
-            // let command_response =
+            if deferred {
+                if received.elapsed() < Handler::INTERACTION_TOKEN_LIFETIME {
+                    Handler::respond_deferred(&ctx, &command, command_response, ephemeral).await;
+                } else {
+                    self.respond_in_channel(&command, command_response).await;
+                }
+                return;
+            }
+
+            let CommandResponse {
+                messages,
+                attachment,
+            } = command_response;
+            let mut messages = messages.into_iter();
+            let first = messages.next().unwrap_or_default();
 
             if let Err(why) = command
                 .create_interaction_response(&ctx.http, |response| {
                     response
                         .kind(InteractionResponseType::ChannelMessageWithSource)
-                        .interaction_response_data(|message| message.content(command_response))
+                        .interaction_response_data(|message| {
+                            if let Some((filename, data)) = attachment {
+                                message.add_file(AttachmentType::Bytes {
+                                    data: data.into(),
+                                    filename,
+                                });
+                            }
+                            message.content(first).ephemeral(ephemeral)
+                        })
                 })
                 .await
             {
                 log::warn!("Cannot respond to slash command: {}", why);
+                return;
+            }
+
+            Handler::send_followups(&ctx, &command, messages, None, ephemeral).await;
+        } else if let Interaction::Autocomplete(autocomplete) = interaction {
+            let partial = autocomplete
+                .data
+                .options
+                .iter()
+                .find(|option| option.focused && option.name == Handler::INSTANCE_OPTION)
+                .and_then(|option| option.value.as_ref())
+                .and_then(|value| value.as_str())
+                .unwrap_or_default();
+
+            let instance_names = self.instances_for(
+                &autocomplete.data.name,
+                &Caller::from_autocomplete(&autocomplete),
+                partial,
+            );
+
+            if let Err(why) = autocomplete
+                .create_autocomplete_response(&ctx.http, |response| {
+                    for instance_name in instance_names {
+                        response.add_string_choice(instance_name, instance_name);
+                    }
+                    response
+                })
+                .await
+            {
+                log::warn!("Cannot respond to autocomplete: {}", why);
             }
         }
     }
@@ -95,28 +181,152 @@ impl EventHandler for Handler {
     async fn ready(&self, ctx: Context, ready: Ready) {
         log::debug!("{} is connected!", ready.user.name);
 
-        // todo: redesing this spot... set_app_cmd overrides all commands
-        for (instance_name, instance) in self.cfg.instances.to_owned() {
-            let guild_id = GuildId(instance.restrictions.server_id);
+        for (guild_id, commands) in registration::guild_commands(&self.cfg) {
+            registration::register_guild(&ctx.http, guild_id, commands)
+                .await
+                .log(guild_id);
+        }
+    }
+}
 
-            let commands = GuildId::set_application_commands(&guild_id, &ctx.http, |commands| {
-                for (slash_cmd_name, slash_cmd) in instance.slash_commands {
-                    let cmd_name = Handler::make_cmd_name(&instance_name, &slash_cmd_name);
+impl Handler {
+    /// Discord takes edits and follow-ups of an interaction for 15 minutes, this leaves
+    /// time to send them.
+    const INTERACTION_TOKEN_LIFETIME: Duration = Duration::from_secs(14 * 60);
 
-                    commands.create_application_command(|command| {
-                        command.name(cmd_name).description(slash_cmd.description)
-                    });
+    /// Shows the progress of a deferred command in its response, until the command is
+    /// done and the sender dropped or the interaction expires.
+    async fn show_progress(
+        ctx: &Context,
+        command: &ApplicationCommandInteraction,
+        received: Instant,
+        updates: mpsc::Receiver<String>,
+    ) {
+        let mut updates = updates;
+        while let Some(update) = updates.recv().await {
+            if received.elapsed() >= Handler::INTERACTION_TOKEN_LIFETIME {
+                // dropping the receiver lets the command skip further updates
+                return;
+            }
+            if let Err(why) = command
+                .edit_original_interaction_response(&ctx.http, |response| response.content(update))
+                .await
+            {
+                log::warn!("Cannot show progress of slash command: {}", why);
+            }
+        }
+    }
+
+    /// Replaces the "thinking" placeholder of a deferred command with the response.
+    /// The placeholder is public, so ephemeral responses replace it with a follow-up.
+    async fn respond_deferred(
+        ctx: &Context,
+        command: &ApplicationCommandInteraction,
+        response: CommandResponse,
+        ephemeral: bool,
+    ) {
+        let CommandResponse {
+            messages,
+            attachment,
+        } = response;
+        let mut messages = messages.into_iter();
+
+        if ephemeral {
+            if let Err(why) = command
+                .delete_original_interaction_response(&ctx.http)
+                .await
+            {
+                log::warn!("Cannot delete deferred response: {}", why);
+            }
+        } else {
+            let first = messages.next().unwrap_or_default();
+            if let Err(why) = command
+                .edit_original_interaction_response(&ctx.http, |response| response.content(first))
+                .await
+            {
+                log::warn!("Cannot respond to deferred slash command: {}", why);
+                return;
+            }
+        }
+
+        Handler::send_followups(ctx, command, messages, attachment, ephemeral).await;
+    }
+
+    /// Posts the response of a command that outlived its interaction to the channel it
+    /// was called from, as the placeholder can't be replaced anymore. Ephemeral responses
+    /// are posted too, the caller would miss them otherwise.
+    async fn respond_in_channel(
+        &self,
+        command: &ApplicationCommandInteraction,
+        response: CommandResponse,
+    ) {
+        let CommandResponse {
+            messages,
+            attachment,
+        } = response;
+        let channel = command.channel_id;
+        let mut messages = messages.into_iter();
+        // mentions the caller, who is waiting for the placeholder
+        let first = format!(
+            "<@{}> {}",
+            command.user.id,
+            messages.next().unwrap_or_default()
+        );
+
+        match attachment {
+            Some((filename, data)) => {
+                if let Err(why) = channel
+                    .send_message(&self.http, |message| {
+                        message
+                            .add_file(AttachmentType::Bytes {
+                                data: data.into(),
+                                filename,
+                            })
+                            .content(first)
+                    })
+                    .await
+                {
+                    log::warn!("Cannot post response of slash command: {}", why);
                 }
-                log::trace!("{:#?}", commands);
+            }
+            None => self.send_discord_message(channel, first).await,
+        }
+        for msg in messages {
+            self.send_discord_message(channel, msg).await;
+        }
+    }
 
-                commands
-            })
-            .await;
+    /// Sends the remaining messages of a response, the first one carrying the attachment.
+    async fn send_followups(
+        ctx: &Context,
+        command: &ApplicationCommandInteraction,
+        messages: impl Iterator<Item = String>,
+        attachment: Option<(String, Vec<u8>)>,
+        ephemeral: bool,
+    ) {
+        let mut attachment = attachment;
+        let mut messages: Vec<String> = messages.collect();
+        if attachment.is_some() && messages.is_empty() {
+            messages.push(String::new());
+        }
 
-            log::trace!(
-                "I now have the following guild slash commands: {:#?}",
-                commands
-            );
+        for msg in messages {
+            let file = attachment.take();
+            if let Err(why) = command
+                .create_followup_message(&ctx.http, |message| {
+                    if let Some((filename, data)) = file {
+                        message.add_file(AttachmentType::Bytes {
+                            data: data.into(),
+                            filename,
+                        });
+                    }
+                    message.content(msg).ephemeral(ephemeral)
+                })
+                .await
+            {
+                log::warn!("Cannot send follow-up to slash command: {}", why);
+                return;
+            }
         }
     }
 }
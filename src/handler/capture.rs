@@ -0,0 +1,92 @@
+use std::{sync::PoisonError, time::Duration};
+
+use tokio::{sync::mpsc::Sender, time::timeout};
+
+use super::commands::CommandResponse;
+use super::logs::output_response;
+use super::Handler;
+use crate::config::bot::CaptureConfig;
+use crate::instance::InstanceInEvents;
+
+impl Handler {
+    /// Writes `cmd` to the child and answers with the output following it, headed by
+    /// `reply`. The output is read from the log buffer, so other output written in the
+    /// meantime ends up in the answer too.
+    pub async fn capture_stdin_command(
+        &self,
+        instance_name: &str,
+        sender: &Sender<InstanceInEvents>,
+        cmd: String,
+        reply: String,
+        capture: &CaptureConfig,
+    ) -> Result<CommandResponse, String> {
+        let logs = self
+            .logs
+            .get(instance_name)
+            .ok_or_else(|| format!("There is no instance named `{instance_name}`."))?;
+        let (mut position, mut pushed) = {
+            let logs = logs.lock().unwrap_or_else(PoisonError::into_inner);
+            (logs.position(), logs.subscribe())
+        };
+
+        sender
+            .send(InstanceInEvents::ExecuteStdinCommand(cmd))
+            .await
+            .map_err(|err| err.to_string())?;
+
+        let mut lines = Vec::new();
+        let mut failed = false;
+        let collect = async {
+            loop {
+                let (new_lines, _, next_position) = logs
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .read_from(position);
+                position = next_position;
+
+                for line in new_lines {
+                    failed = capture
+                        .error_pattern
+                        .as_ref()
+                        .is_some_and(|error| error.is_match(&line.text));
+                    let done = failed
+                        || capture
+                            .until
+                            .as_ref()
+                            .is_some_and(|until| until.is_match(&line.text));
+                    lines.push(line.text);
+
+                    if done || capture.lines.is_some_and(|max| lines.len() >= max) {
+                        return;
+                    }
+                }
+                // the buffer outlives the capture, the sender can't be gone
+                if pushed.changed().await.is_err() {
+                    return;
+                }
+            }
+        };
+        let completed = timeout(Duration::from_secs(capture.timeout), collect)
+            .await
+            .is_ok();
+
+        let mut header = if failed {
+            format!("`{instance_name}` answered with an error:")
+        } else {
+            reply
+        };
+        // without an end to wait for, the timeout is how long the answer takes
+        if !completed && capture.until.is_some() {
+            header.push_str(&format!(" (timed out after {}s)", capture.timeout));
+        }
+        if lines.is_empty() {
+            return Ok(format!("{header}\nNo output within {}s.", capture.timeout).into());
+        }
+
+        Ok(output_response(
+            header,
+            lines.join("\n"),
+            format!("{instance_name}.log"),
+        ))
+    }
+}
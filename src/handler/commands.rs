@@ -0,0 +1,323 @@
+use std::{collections::HashMap, time::Duration};
+
+use chrono::Local;
+use serenity::model::application::interaction::application_command::CommandDataOption;
+use serenity::model::prelude::ChannelId;
+use tokio::{
+    sync::{mpsc::Sender, oneshot},
+    time::timeout,
+};
+
+use super::options;
+use super::state::{InstanceState, InstanceStatus};
+use super::{ActiveInstance, Handler};
+use crate::config::bot::SlashCommandConfig;
+use crate::instance::{Instance, InstanceInEvents, InstanceRunner};
+
+/// What a command answers with. Every message is sent on its own, the first one
+/// also carries the attachment.
+#[derive(Debug, Default)]
+pub struct CommandResponse {
+    pub messages: Vec<String>,
+    // file name and content
+    pub attachment: Option<(String, Vec<u8>)>,
+}
+
+impl From<String> for CommandResponse {
+    fn from(msg: String) -> Self {
+        CommandResponse {
+            messages: vec![msg],
+            attachment: None,
+        }
+    }
+}
+
+impl Handler {
+    /// Time a runner gets to answer a status request, it might be busy stopping its child.
+    const STATUS_TIMEOUT: Duration = Duration::from_secs(2);
+    pub const RESERVED_COMMANDS: [&'static str; 8] = [
+        "start", "stop", "restart", "kill", "status", "logs", "cancel", "schedule",
+    ];
+
+    /// Whether the answer takes longer than discord waits for a response, so it has
+    /// to be deferred.
+    pub fn is_deferred(slash_cmd_name: &str, slash_cmd: &SlashCommandConfig) -> bool {
+        !Self::RESERVED_COMMANDS.contains(&slash_cmd_name)
+            && (slash_cmd
+                .stdin
+                .as_ref()
+                .is_some_and(|stdin| stdin.capture.is_some())
+                || slash_cmd.stdin.is_none()
+                    && (slash_cmd.exec.is_some() || slash_cmd.workflow.is_some()))
+    }
+
+    /// Runs a slash command for an instance. An `Err` is only shown to the caller.
+    ///
+    /// `start`, `stop`, `restart`, `kill`, `status`, `logs`, `cancel` and `schedule` are reserved,
+    /// every other command writes its `stdin` config to the child, runs its `exec` program
+    /// or its `workflow`, with its options filled in. `progress` takes updates shown while
+    /// a deferred command runs.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn run_command(
+        &self,
+        instance_name: &str,
+        instance: &Instance,
+        slash_cmd_name: &str,
+        slash_cmd: &SlashCommandConfig,
+        options: &[CommandDataOption],
+        channel: ChannelId,
+        progress: &Sender<String>,
+    ) -> Result<CommandResponse, String> {
+        match slash_cmd_name {
+            "start" => self
+                .start_instance(instance_name, instance, channel)
+                .await
+                .map(CommandResponse::from),
+            "stop" => {
+                self.send_lifecycle_event(
+                    instance_name,
+                    InstanceInEvents::Stop,
+                    InstanceState::Stopping,
+                    // also cancels a pending restart
+                    InstanceStatus::validate_active,
+                )
+                .await?;
+                Ok(match &slash_cmd.stdin {
+                    Some(stdin) => stdin.interaction_msg.replace("{}", instance_name),
+                    None => format!("Stopping `{instance_name}`."),
+                }
+                .into())
+            }
+            "restart" => {
+                self.send_lifecycle_event(
+                    instance_name,
+                    InstanceInEvents::Restart,
+                    InstanceState::Restarting,
+                    InstanceStatus::validate_restart,
+                )
+                .await?;
+                Ok(format!("Restarting `{instance_name}`.").into())
+            }
+            "kill" => {
+                self.send_lifecycle_event(
+                    instance_name,
+                    InstanceInEvents::Kill,
+                    InstanceState::Stopping,
+                    InstanceStatus::validate_active,
+                )
+                .await?;
+                Ok(format!("Killing `{instance_name}`.").into())
+            }
+            "status" => Ok(self.instance_status(instance_name).await.into()),
+            "logs" => self.instance_logs(instance_name, options),
+            "cancel" => self
+                .cancel_workflow(instance_name)
+                .map(CommandResponse::from),
+            "schedule" => self
+                .schedule_command(instance_name, options)
+                .map(CommandResponse::from),
+            _ => {
+                let values = options::option_values(&slash_cmd.options, options)?;
+                self.run_custom_command(
+                    instance_name,
+                    instance,
+                    slash_cmd_name,
+                    slash_cmd,
+                    values,
+                    channel,
+                    progress,
+                )
+                .await
+            }
+        }
+    }
+
+    /// Runs a command that isn't reserved, with the values of its options.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn run_custom_command(
+        &self,
+        instance_name: &str,
+        instance: &Instance,
+        slash_cmd_name: &str,
+        slash_cmd: &SlashCommandConfig,
+        values: HashMap<String, String>,
+        channel: ChannelId,
+        progress: &Sender<String>,
+    ) -> Result<CommandResponse, String> {
+        if let Some(stdin) = &slash_cmd.stdin {
+            let cmd = options::render(&stdin.cmd, &values);
+            let reply =
+                options::render(&stdin.interaction_msg, &values).replace("{}", instance_name);
+            let sender = self.running_sender(instance_name).await?;
+
+            match &stdin.capture {
+                Some(capture) => {
+                    self.capture_stdin_command(instance_name, &sender, cmd, reply, capture)
+                        .await
+                }
+                None => match sender
+                    .send(InstanceInEvents::ExecuteStdinCommand(cmd))
+                    .await
+                {
+                    Ok(()) => Ok(reply.into()),
+                    Err(err) => Err(err.to_string()),
+                },
+            }
+        } else if let Some(exec) = &slash_cmd.exec {
+            self.run_exec_command(
+                instance_name,
+                instance,
+                slash_cmd_name,
+                exec,
+                &values,
+                progress,
+            )
+            .await
+        } else if let Some(steps) = &slash_cmd.workflow {
+            self.run_workflow(
+                instance_name,
+                slash_cmd_name,
+                steps,
+                values,
+                channel,
+                progress,
+            )
+            .await
+        } else {
+            Err(String::from("not currently supported or implemented (5)"))
+        }
+    }
+
+    pub async fn start_instance(
+        &self,
+        instance_name: &str,
+        instance: &Instance,
+        channel: ChannelId,
+    ) -> Result<String, String> {
+        log::debug!("Start command received for [{instance_name}]");
+        let mut states = self.states.lock().await;
+        // checked under the lock, the shutdown takes it before collecting the instances
+        if !self.is_accepting_commands() {
+            return Err(format!(
+                "macobot is shutting down, `{instance_name}` can't be started anymore."
+            ));
+        }
+        let status = states.entry(instance_name.to_string()).or_default();
+
+        status.validate_start(instance_name)?;
+        status.transition(instance_name, InstanceState::Starting);
+        self.state_changed.send_replace(());
+        self.active_instances.lock().await.insert(
+            instance_name.to_string(),
+            ActiveInstance {
+                sender: InstanceRunner::new(
+                    instance_name.to_string(),
+                    instance.clone(),
+                    self.logs[instance_name].clone(),
+                    self.sender.clone(),
+                ),
+                channel,
+                pid: None,
+                log_stream: None,
+            },
+        );
+
+        Ok(format!(
+            "Starting `{instance_name}`. Will send a message after startup."
+        ))
+    }
+
+    /// Validates the state, moves on to the given one and hands the event to the runner.
+    pub async fn send_lifecycle_event(
+        &self,
+        instance_name: &str,
+        event: InstanceInEvents,
+        state: InstanceState,
+        validate: fn(&InstanceStatus, &str) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let sender = {
+            let mut states = self.states.lock().await;
+            let status = states.entry(instance_name.to_string()).or_default();
+            validate(status, instance_name)?;
+
+            let sender = match self.active_instances.lock().await.get(instance_name) {
+                Some(active_instance) => active_instance.sender.clone(),
+                None => {
+                    return Err(format!(
+                        "There is no running instance for `{instance_name}`."
+                    ))
+                }
+            };
+            status.transition(instance_name, state);
+            self.state_changed.send_replace(());
+            sender
+        };
+
+        sender.send(event).await.map_err(|err| {
+            log::error!("[{instance_name}] Runner didn't accept {:?}", err.0);
+            format!("Couldn't reach the runner of `{instance_name}`.")
+        })
+    }
+
+    async fn instance_status(&self, instance_name: &str) -> String {
+        let status = self
+            .states
+            .lock()
+            .await
+            .get(instance_name)
+            .cloned()
+            .unwrap_or_default();
+        let sender = self
+            .active_instances
+            .lock()
+            .await
+            .get(instance_name)
+            .map(|active_instance| active_instance.sender.clone());
+
+        let mut lines = vec![format!("`{instance_name}` is {status}.")];
+        let mut last_exit = status.last_exit;
+
+        if let Some(sender) = sender {
+            let (reply, response) = oneshot::channel();
+            if sender.try_send(InstanceInEvents::Status(reply)).is_ok() {
+                if let Ok(Ok(runner)) = timeout(Self::STATUS_TIMEOUT, response).await {
+                    lines.push(format!("PID: {}", runner.pid));
+                    lines.push(format!(
+                        "Uptime: {}",
+                        format_duration(Local::now() - runner.started_at)
+                    ));
+                    last_exit = runner.last_exit.or(last_exit);
+                    if !runner.health_checks.is_empty() {
+                        lines.push(String::from("Health checks:"));
+                        lines.extend(
+                            runner
+                                .health_checks
+                                .iter()
+                                .rev()
+                                .map(|result| format!("- {result}")),
+                        );
+                    }
+                }
+            }
+        }
+
+        if let Some(last_exit) = last_exit {
+            lines.push(format!("Last exit: {last_exit}"));
+        }
+
+        lines.join("\n")
+    }
+}
+
+pub fn format_duration(duration: chrono::Duration) -> String {
+    let secs = duration.num_seconds().max(0);
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+
+    if hours > 0 {
+        format!("{hours}h {minutes:02}m {secs:02}s")
+    } else if minutes > 0 {
+        format!("{minutes}m {secs:02}s")
+    } else {
+        format!("{secs}s")
+    }
+}
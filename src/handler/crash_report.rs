@@ -0,0 +1,102 @@
+use serenity::model::channel::AttachmentType;
+use serenity::utils::Colour;
+
+use super::commands::format_duration;
+use super::logs::code_blocks;
+use super::Handler;
+use crate::instance::crash::{CrashOutcome, CrashReport};
+
+impl Handler {
+    /// Discord rejects embed field values longer than this.
+    const MAX_FIELD_LENGTH: usize = 1024;
+
+    /// Posts an embed with the report to the instance channel, with the run log attached.
+    /// It also tells what happens next, there is no separate message about it.
+    pub async fn send_crash_report(&self, instance_name: &str, report: CrashReport) {
+        let channel = match self.instance_channel(instance_name).await {
+            Some(channel) => channel,
+            None => return,
+        };
+
+        let uptime = format_duration(report.exited_at - report.started_at);
+        let next = match report.outcome {
+            CrashOutcome::Stopped => String::from("Stays stopped."),
+            CrashOutcome::Restarting { attempt, delay } => format!(
+                "Restarting in {} (attempt {attempt}).",
+                format_duration(chrono::Duration::seconds(delay.as_secs() as i64))
+            ),
+            CrashOutcome::RestartsExhausted(attempts) => format!(
+                "Already restarted {attempts} times. Giving up, the instance is marked as failed."
+            ),
+        };
+        let mut command: String = report
+            .command
+            .chars()
+            .take(Self::MAX_FIELD_LENGTH - 2)
+            .collect();
+        command = format!("`{}`", command.replace('`', "'"));
+        let last_lines = report
+            .last_lines
+            .iter()
+            .map(|line| line.format(false))
+            .collect::<Vec<_>>()
+            .join("\n");
+        // only the end fits into a single message
+        let last_lines = code_blocks(&last_lines).pop();
+
+        let mut run_log = String::new();
+        if report.dropped > 0 {
+            run_log.push_str(&format!(
+                "[{} earlier lines of this run were dropped from the log buffer]\n",
+                report.dropped
+            ));
+        }
+        for line in &report.run_log {
+            run_log.push_str(&line.format(true));
+            run_log.push('\n');
+        }
+        let filename = format!(
+            "{instance_name}-{}.log",
+            report.exited_at.format("%Y%m%d-%H%M%S")
+        );
+
+        let res = channel
+            .send_message(&self.http, |m| {
+                m.embed(|embed| {
+                    embed
+                        .title(format!("`{instance_name}` crashed"))
+                        .colour(Colour::RED)
+                        .field("Exit", &report.exit, true)
+                        .field("Uptime", &uptime, true)
+                        .field("Command", command, false)
+                        .field("Next", &next, false)
+                        .timestamp(report.exited_at.to_rfc3339());
+                    if let Some(last_lines) = last_lines {
+                        embed.description(last_lines);
+                    }
+                    embed
+                });
+                if !report.run_log.is_empty() {
+                    m.add_file(AttachmentType::Bytes {
+                        data: run_log.into_bytes().into(),
+                        filename,
+                    });
+                }
+                m
+            })
+            .await;
+
+        if let Err(err) = res {
+            log::error!("[{instance_name}] Couldn't send crash report: {err}");
+            // the outbox only knows text, at least the summary should arrive
+            self.send_discord_message(
+                channel,
+                format!(
+                    "`{instance_name}` crashed with {} after {uptime}. {next}",
+                    report.exit
+                ),
+            )
+            .await;
+        }
+    }
+}
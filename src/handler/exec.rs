@@ -0,0 +1,146 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    sync::mpsc::Sender,
+    time::{interval_at, sleep, timeout},
+};
+
+use super::commands::CommandResponse;
+use super::logs::{code_blocks, output_response, MAX_MESSAGE_LENGTH};
+use super::{options, Handler};
+use crate::config::bot::ExecConfig;
+use crate::instance::{
+    crash::describe_exit,
+    exec::{ExecEvent, ExecRun},
+    Instance,
+};
+
+impl Handler {
+    /// How often a running exec command reports its progress.
+    const EXEC_PROGRESS_INTERVAL: Duration = Duration::from_secs(3);
+    /// Lines of output shown with the progress.
+    const EXEC_PROGRESS_LINES: usize = 5;
+    /// Time left to read the output of a program that exited.
+    const EXEC_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// Runs the program of an exec command, reporting its progress until it exits or
+    /// times out. Answers with how it exited and the last lines of its output.
+    pub async fn run_exec_command(
+        &self,
+        instance_name: &str,
+        instance: &Instance,
+        slash_cmd_name: &str,
+        exec: &ExecConfig,
+        values: &HashMap<String, String>,
+        progress: &Sender<String>,
+    ) -> Result<CommandResponse, String> {
+        let label = format!("`/{slash_cmd_name}` on `{instance_name}`");
+        let args: Vec<String> = exec
+            .cmd_args
+            .iter()
+            .map(|arg| options::render(arg, values))
+            .collect();
+
+        let lock = self
+            .exec_locks
+            .get(&(instance_name.to_string(), slash_cmd_name.to_string()))
+            .ok_or_else(|| format!("{label} doesn't run a program."))?;
+        let _running = match lock.clone().try_lock_owned() {
+            Ok(running) => running,
+            Err(_) => {
+                // progress is best effort, a full channel just skips an update
+                let _ = progress.try_send(format!(
+                    "Waiting for the previous run of {label} to finish."
+                ));
+                lock.clone().lock_owned().await
+            }
+        };
+
+        log::info!(
+            "[{instance_name}] Running `{}` for `/{slash_cmd_name}`",
+            exec.cmd_path
+        );
+        let started = Instant::now();
+        let mut run = ExecRun::spawn(instance, &exec.cmd_path, &args)?;
+        let _ = progress.try_send(format!("Running {label}."));
+
+        let kept_lines = exec.tail_lines.max(Self::EXEC_PROGRESS_LINES);
+        let mut tail = VecDeque::with_capacity(kept_lines);
+        let push = |tail: &mut VecDeque<String>, text: String| {
+            if tail.len() >= kept_lines {
+                tail.pop_front();
+            }
+            tail.push_back(text);
+        };
+
+        let deadline = sleep(Duration::from_secs(exec.timeout));
+        tokio::pin!(deadline);
+        let mut ticks = interval_at(
+            (started + Self::EXEC_PROGRESS_INTERVAL).into(),
+            Self::EXEC_PROGRESS_INTERVAL,
+        );
+
+        let end = loop {
+            tokio::select! {
+                event = run.next_event() => match event {
+                    ExecEvent::Line(line) => push(&mut tail, line.text),
+                    ExecEvent::Exited(status) => {
+                        break status.map_err(|err| format!("couldn't be waited for: {err}"));
+                    }
+                },
+                _ = &mut deadline => {
+                    if let Err(err) = run.kill().await {
+                        log::error!("[{instance_name}] Couldn't wait for `{}`: {err}", exec.cmd_path);
+                    }
+                    break Err(format!("timed out after {}s and was killed", exec.timeout));
+                }
+                _ = ticks.tick() => {
+                    let _ = progress.try_send(Self::exec_progress(&label, started, &tail));
+                }
+            }
+        };
+        let took = started.elapsed().as_secs();
+
+        // output written right before the exit might still be in the pipes
+        let _ = timeout(Self::EXEC_DRAIN_TIMEOUT, async {
+            while let Some(line) = run.next_line().await {
+                push(&mut tail, line.text);
+            }
+        })
+        .await;
+
+        let outcome = match end {
+            Ok(status) => format!("exited with {} after {took}s", describe_exit(&status)),
+            Err(reason) => reason,
+        };
+        log::info!("[{instance_name}] `{}` {outcome}", exec.cmd_path);
+
+        let header = format!("{label} {outcome}.");
+        let skip = tail.len().saturating_sub(exec.tail_lines);
+        let lines: Vec<String> = tail.into_iter().skip(skip).collect();
+        if lines.is_empty() {
+            return Ok(header.into());
+        }
+        Ok(output_response(
+            header,
+            lines.join("\n"),
+            format!("{instance_name}-{slash_cmd_name}.log"),
+        ))
+    }
+
+    fn exec_progress(label: &str, started: Instant, tail: &VecDeque<String>) -> String {
+        let running = format!("Running {label} for {}s.", started.elapsed().as_secs());
+        let skip = tail.len().saturating_sub(Self::EXEC_PROGRESS_LINES);
+        let lines: Vec<&str> = tail.iter().skip(skip).map(String::as_str).collect();
+
+        match code_blocks(&lines.join("\n")).pop() {
+            Some(block) if running.len() + 1 + block.len() <= MAX_MESSAGE_LENGTH => {
+                format!("{running}\n{block}")
+            }
+            _ => running,
+        }
+    }
+}
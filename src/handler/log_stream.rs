@@ -0,0 +1,195 @@
+use std::sync::{Arc, PoisonError};
+use std::time::Duration;
+
+use chrono::Local;
+use regex::Regex;
+use serenity::model::prelude::{ChannelId, GuildChannel};
+use tokio::sync::oneshot;
+use tokio::time::{interval, MissedTickBehavior};
+
+use super::logs::code_blocks;
+use super::Handler;
+use crate::config::bot::{ConfigRegex, LogStreamConfig};
+use crate::instance::logs::{LogLine, SharedLogBuffer};
+
+/// A running log stream. Dropping it ends the stream, the rest of the output is
+/// still sent and the thread archived.
+pub struct LogStream {
+    _stop: oneshot::Sender<()>,
+}
+
+/// Decides which lines are streamed and how they look.
+struct LineFilter {
+    include: Vec<ConfigRegex>,
+    exclude: Vec<ConfigRegex>,
+    ansi: Option<Regex>,
+}
+
+impl LineFilter {
+    fn new(cfg: &LogStreamConfig) -> LineFilter {
+        LineFilter {
+            include: cfg.include.clone(),
+            exclude: cfg.exclude.clone(),
+            // CSI sequences like colors and cursor movement, OSC sequences like titles
+            ansi: cfg.strip_ansi.then(|| {
+                Regex::new(r"\x1b\[[0-?]*[ -/]*[@-~]|\x1b\][^\x07\x1b]*(\x07|\x1b\\)|\x1b[@-_]")
+                    .expect("ANSI pattern is valid")
+            }),
+        }
+    }
+
+    fn apply(&self, mut line: LogLine) -> Option<LogLine> {
+        if let Some(ansi) = &self.ansi {
+            line.text = ansi.replace_all(&line.text, "").into_owned();
+        }
+
+        let included = self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|pattern| pattern.is_match(&line.text));
+        let excluded = self
+            .exclude
+            .iter()
+            .any(|pattern| pattern.is_match(&line.text));
+        (included && !excluded).then_some(line)
+    }
+}
+
+impl Handler {
+    /// Messages per batch, older lines of a batch are dropped beyond it.
+    const MAX_BATCH_MESSAGES: usize = 5;
+
+    /// Streams the output of a new run into a thread of the instance channel,
+    /// starting with the line at `position`, the first one of the run.
+    pub fn start_log_stream(
+        handler: Arc<Self>,
+        instance_name: String,
+        channel: ChannelId,
+        cfg: LogStreamConfig,
+        position: u64,
+    ) -> Option<LogStream> {
+        let logs = handler.logs.get(&instance_name)?.clone();
+        let (stop, stopped) = oneshot::channel();
+
+        tokio::spawn(async move {
+            handler
+                .stream_logs(&instance_name, channel, &cfg, logs, position, stopped)
+                .await
+        });
+
+        Some(LogStream { _stop: stop })
+    }
+
+    async fn stream_logs(
+        &self,
+        instance_name: &str,
+        channel: ChannelId,
+        cfg: &LogStreamConfig,
+        logs: SharedLogBuffer,
+        position: u64,
+        mut stopped: oneshot::Receiver<()>,
+    ) {
+        let filter = LineFilter::new(cfg);
+        let thread = match self.create_log_thread(instance_name, channel).await {
+            Some(thread) => thread,
+            None => return,
+        };
+        log::debug!("[{instance_name}] Streaming output to thread {}", thread.id);
+
+        let mut position = position;
+        let mut ticks = interval(Duration::from_secs(cfg.interval.max(1)));
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ticks.tick().await;
+
+        loop {
+            // the stream also ends once the handle is dropped
+            let finished = tokio::select! {
+                _ = ticks.tick() => false,
+                _ = &mut stopped => true,
+            };
+
+            let (lines, dropped, next_position) = logs
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .read_from(position);
+            position = next_position;
+            self.send_batch(thread.id, &filter, lines, dropped).await;
+
+            if finished {
+                break;
+            }
+        }
+
+        log::debug!("[{instance_name}] Archiving output thread {}", thread.id);
+        if let Err(err) = thread
+            .id
+            .edit_thread(&self.http, |edit| edit.archived(true))
+            .await
+        {
+            log::warn!("[{instance_name}] Couldn't archive output thread: {err}");
+        }
+    }
+
+    async fn create_log_thread(
+        &self,
+        instance_name: &str,
+        channel: ChannelId,
+    ) -> Option<GuildChannel> {
+        let started = Local::now().format("%Y-%m-%d %H:%M");
+        let starter = channel
+            .send_message(&self.http, |m| {
+                m.content(format!("Output of `{instance_name}`, started {started}"))
+            })
+            .await;
+        let thread = match starter {
+            Ok(starter) => {
+                channel
+                    .create_public_thread(&self.http, starter.id, |thread| {
+                        thread.name(format!("{instance_name} {started}"))
+                    })
+                    .await
+            }
+            Err(err) => Err(err),
+        };
+
+        thread
+            .map_err(|err| {
+                log::error!("[{instance_name}] Couldn't create output thread: {err}");
+            })
+            .ok()
+    }
+
+    async fn send_batch(
+        &self,
+        thread: ChannelId,
+        filter: &LineFilter,
+        lines: Vec<LogLine>,
+        dropped: u64,
+    ) {
+        let text = lines
+            .into_iter()
+            .filter_map(|line| filter.apply(line))
+            .map(|line| line.format(false))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let mut blocks = code_blocks(&text);
+
+        let mut skipped_msgs = 0;
+        if blocks.len() > Self::MAX_BATCH_MESSAGES {
+            skipped_msgs = blocks.len() - Self::MAX_BATCH_MESSAGES;
+            blocks.drain(..skipped_msgs);
+        }
+        if dropped > 0 || skipped_msgs > 0 {
+            self.send_discord_message(
+                thread,
+                format!("Output is too fast to stream, skipped {dropped} lines and {skipped_msgs} messages."),
+            )
+            .await;
+        }
+
+        for block in blocks {
+            self.send_discord_message(thread, block).await;
+        }
+    }
+}
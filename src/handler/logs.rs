@@ -0,0 +1,179 @@
+use std::sync::PoisonError;
+
+use chrono::{DateTime, Duration, Local, NaiveTime};
+use regex::Regex;
+use serenity::model::application::interaction::application_command::CommandDataOption;
+
+use super::commands::CommandResponse;
+use super::Handler;
+
+/// Discord rejects messages longer than this.
+pub const MAX_MESSAGE_LENGTH: usize = 2000;
+
+impl Handler {
+    pub const LINES_OPTION: &'static str = "lines";
+    pub const GREP_OPTION: &'static str = "grep";
+    pub const SINCE_OPTION: &'static str = "since";
+    const DEFAULT_LOG_LINES: usize = 20;
+    /// Answers that would take more messages are sent as a file.
+    const MAX_LOG_MESSAGES: usize = 3;
+
+    /// Answers `/logs` with the buffered output of an instance.
+    pub fn instance_logs(
+        &self,
+        instance_name: &str,
+        options: &[CommandDataOption],
+    ) -> Result<CommandResponse, String> {
+        let option = |name: &str| {
+            options
+                .iter()
+                .find(|option| option.name == name)
+                .and_then(|option| option.value.as_ref())
+        };
+
+        let count = option(Self::LINES_OPTION)
+            .and_then(|value| value.as_u64())
+            .map_or(Self::DEFAULT_LOG_LINES, |count| count as usize);
+        let grep = option(Self::GREP_OPTION)
+            .and_then(|value| value.as_str())
+            .map(|grep| Regex::new(grep).map_err(|err| format!("Invalid `grep`: {err}")))
+            .transpose()?;
+        let since = option(Self::SINCE_OPTION)
+            .and_then(|value| value.as_str())
+            .map(parse_since)
+            .transpose()?;
+
+        let logs = self
+            .logs
+            .get(instance_name)
+            .ok_or_else(|| format!("There is no instance named `{instance_name}`."))?;
+        let lines =
+            logs.lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .query(count, grep.as_ref(), since);
+
+        if lines.is_empty() {
+            return Ok(format!("No matching output of `{instance_name}`.").into());
+        }
+
+        let text = lines
+            .iter()
+            .map(|line| line.format(false))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let messages = code_blocks(&text);
+        if messages.len() <= Self::MAX_LOG_MESSAGES {
+            return Ok(CommandResponse {
+                messages,
+                attachment: None,
+            });
+        }
+
+        let file = lines
+            .iter()
+            .map(|line| line.format(true))
+            .collect::<Vec<_>>()
+            .join("\n");
+        Ok(CommandResponse {
+            messages: vec![format!(
+                "The last {} lines of `{instance_name}`:",
+                lines.len()
+            )],
+            attachment: Some((format!("{instance_name}.log"), file.into_bytes())),
+        })
+    }
+}
+
+/// `header` followed by `text` in code blocks, sharing the first message where it fits.
+/// Output that would take too many messages is sent as `file_name` instead.
+pub fn output_response(header: String, text: String, file_name: String) -> CommandResponse {
+    let mut blocks = code_blocks(&text);
+    if blocks.len() > Handler::MAX_LOG_MESSAGES {
+        return CommandResponse {
+            messages: vec![header],
+            attachment: Some((file_name, text.into_bytes())),
+        };
+    }
+
+    match blocks.first_mut() {
+        Some(first) if header.len() + 1 + first.len() <= MAX_MESSAGE_LENGTH => {
+            *first = format!("{header}\n{first}");
+        }
+        _ => blocks.insert(0, header),
+    }
+    CommandResponse {
+        messages: blocks,
+        attachment: None,
+    }
+}
+
+/// Splits text into code blocks that fit into a message each, breaking between lines
+/// where possible. Lines that are too long on their own are cut.
+pub fn code_blocks(text: &str) -> Vec<String> {
+    const FENCE: &str = "```";
+    let max = MAX_MESSAGE_LENGTH - 2 * FENCE.len() - 2;
+    // a fence in the output would end the block early
+    let text = text.replace(FENCE, "`\u{200b}``");
+
+    let mut blocks = Vec::new();
+    let mut block = String::new();
+    for line in text.lines() {
+        let line = match line.char_indices().nth(max) {
+            Some((cut, _)) => &line[..cut],
+            None => line,
+        };
+        if !block.is_empty() && block.len() + 1 + line.len() > max {
+            blocks.push(std::mem::take(&mut block));
+        }
+        if !block.is_empty() {
+            block.push('\n');
+        }
+        block.push_str(line);
+    }
+    if !block.is_empty() {
+        blocks.push(block);
+    }
+
+    blocks
+        .into_iter()
+        .map(|block| format!("{FENCE}\n{block}\n{FENCE}"))
+        .collect()
+}
+
+/// Reads `30s`, `10m`, `2h`, `1d` as a time span back from now, `14:02` or `14:02:03`
+/// as the last time it was that time of day.
+fn parse_since(since: &str) -> Result<DateTime<Local>, String> {
+    let since = since.trim();
+    let now = Local::now();
+
+    if let Ok(time) = NaiveTime::parse_from_str(since, "%H:%M")
+        .or_else(|_| NaiveTime::parse_from_str(since, "%H:%M:%S"))
+    {
+        let today = now
+            .date_naive()
+            .and_time(time)
+            .and_local_timezone(Local)
+            .earliest()
+            .ok_or_else(|| format!("`{since}` doesn't exist today"))?;
+        return Ok(if today > now {
+            today - Duration::days(1)
+        } else {
+            today
+        });
+    }
+
+    let invalid = || format!("Invalid `since` `{since}`, use e.g. `10m`, `2h` or `14:02`");
+    let unit_at = since.len() - since.chars().last().map_or(0, char::len_utf8);
+    let (amount, unit) = since.split_at(unit_at);
+    // u32 keeps the span within what chrono can represent
+    let amount = i64::from(amount.parse::<u32>().map_err(|_| invalid())?);
+    let span = match unit {
+        "s" => Duration::seconds(amount),
+        "m" => Duration::minutes(amount),
+        "h" => Duration::hours(amount),
+        "d" => Duration::days(amount),
+        _ => return Err(invalid()),
+    };
+
+    now.checked_sub_signed(span).ok_or_else(invalid)
+}
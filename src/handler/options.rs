@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+
+use serenity::json::Value;
+use serenity::model::application::interaction::application_command::{
+    CommandDataOption, CommandDataOptionValue,
+};
+
+use crate::config::bot::{ChoiceValue, CommandOptionConfig, CommandOptionKind};
+
+/// The values of the configured options of a command, checked against their config
+/// and keyed by option name. Missing optional options are empty.
+pub fn option_values(
+    configured: &[CommandOptionConfig],
+    options: &[CommandDataOption],
+) -> Result<HashMap<String, String>, String> {
+    checked_values(configured, |name| {
+        let option = options.iter().find(|option| option.name == name)?;
+        let user = match &option.resolved {
+            Some(CommandDataOptionValue::User(user, _)) => Some(user.name.as_str()),
+            _ => None,
+        };
+        Some((option.value.as_ref(), user))
+    })
+}
+
+/// Like [`option_values`], for values given without an interaction, e.g. by a schedule.
+pub fn given_values(
+    configured: &[CommandOptionConfig],
+    given: &HashMap<String, Value>,
+) -> Result<HashMap<String, String>, String> {
+    checked_values(configured, |name| Some((given.get(name), None))).and_then(|values| match given
+        .keys()
+        .find(|name| !values.contains_key(*name))
+    {
+        Some(name) => Err(format!("There is no option `{name}`.")),
+        None => Ok(values),
+    })
+}
+
+/// `option` looks up the value of an option and the name of the user it resolved to.
+fn checked_values<'a>(
+    configured: &[CommandOptionConfig],
+    option: impl Fn(&str) -> Option<(Option<&'a Value>, Option<&'a str>)>,
+) -> Result<HashMap<String, String>, String> {
+    configured
+        .iter()
+        .map(|cfg| {
+            let value = match option(&cfg.name) {
+                Some((Some(value), user)) => option_value(cfg, value, user)?,
+                Some((None, _)) | None if cfg.required => {
+                    return Err(format!("Missing `{}`.", cfg.name))
+                }
+                Some((None, _)) | None => String::new(),
+            };
+            // a newline would let the value run its own console command
+            if value.chars().any(char::is_control) {
+                return Err(format!(
+                    "`{}` must not contain newlines or control characters.",
+                    cfg.name
+                ));
+            }
+            Ok((cfg.name.clone(), value))
+        })
+        .collect()
+}
+
+fn option_value(
+    cfg: &CommandOptionConfig,
+    value: &Value,
+    user: Option<&str>,
+) -> Result<String, String> {
+    let name = &cfg.name;
+    let invalid = || format!("Invalid `{name}`.");
+
+    match cfg.kind {
+        CommandOptionKind::String => {
+            let value = value.as_str().ok_or_else(invalid)?;
+            let length = value.chars().count();
+            if cfg.min_length.is_some_and(|min| length < usize::from(min))
+                || cfg.max_length.is_some_and(|max| length > usize::from(max))
+            {
+                return Err(format!("`{name}` has the wrong length."));
+            }
+            if !cfg.choices.is_empty()
+                && !cfg
+                    .choices
+                    .contains(&ChoiceValue::String(value.to_string()))
+            {
+                return Err(invalid());
+            }
+            if let Some(regex) = &cfg.regex {
+                if !regex.is_match(value) {
+                    return Err(format!("`{name}` must match `{}`.", regex.as_str()));
+                }
+            }
+            Ok(value.to_string())
+        }
+        CommandOptionKind::Integer => {
+            let value = value.as_i64().ok_or_else(invalid)?;
+            if cfg.min.is_some_and(|min| value < min) || cfg.max.is_some_and(|max| value > max) {
+                return Err(format!("`{name}` is out of range."));
+            }
+            let is_choice = |choice: &ChoiceValue| matches!(choice, ChoiceValue::Integer(choice) if i64::from(*choice) == value);
+            if !cfg.choices.is_empty() && !cfg.choices.iter().any(is_choice) {
+                return Err(invalid());
+            }
+            Ok(value.to_string())
+        }
+        CommandOptionKind::Boolean => value
+            .as_bool()
+            .map(|value| value.to_string())
+            .ok_or_else(invalid),
+        CommandOptionKind::User => match user {
+            Some(user) => Ok(user.to_string()),
+            None => value.as_str().map(str::to_string).ok_or_else(invalid),
+        },
+    }
+}
+
+/// Replaces `{name}` with the value of the option, in a single pass so values can't
+/// pull in other placeholders. Unknown placeholders are kept as they are.
+pub fn render(template: &str, values: &HashMap<String, String>) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let value = rest
+            .find('}')
+            .and_then(|end| values.get(&rest[1..end]).map(|value| (end, value)));
+        match value {
+            Some((end, value)) => {
+                rendered.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                rendered.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    rendered.push_str(rest);
+
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn string_option(name: &str) -> CommandOptionConfig {
+        CommandOptionConfig {
+            name: name.to_string(),
+            kind: CommandOptionKind::String,
+            description: String::new(),
+            required: true,
+            choices: Vec::new(),
+            regex: None,
+            min: None,
+            max: None,
+            min_length: None,
+            max_length: None,
+        }
+    }
+
+    fn given(name: &str, value: &str) -> HashMap<String, Value> {
+        HashMap::from([(name.to_string(), Value::from(value))])
+    }
+
+    #[test]
+    fn render_fills_in_values() {
+        let values = values(&[("player", "steve"), ("reason", "afk")]);
+        assert_eq!(render("kick {player} {reason}", &values), "kick steve afk");
+    }
+
+    #[test]
+    fn render_keeps_placeholders_in_values() {
+        let values = values(&[("player", "{reason}"), ("reason", "afk")]);
+        assert_eq!(render("kick {player}", &values), "kick {reason}");
+    }
+
+    #[test]
+    fn render_keeps_nested_braces_in_values() {
+        let values = values(&[("data", "{\"a\": {\"b\": 1}}")]);
+        assert_eq!(
+            render("tellraw @a {data}", &values),
+            "tellraw @a {\"a\": {\"b\": 1}}"
+        );
+    }
+
+    #[test]
+    fn render_keeps_unknown_placeholders() {
+        let values = values(&[("player", "steve")]);
+        assert_eq!(
+            render("{} {unknown} {player} {", &values),
+            "{} {unknown} steve {"
+        );
+    }
+
+    #[test]
+    fn control_characters_are_rejected() {
+        let configured = [string_option("player")];
+        for value in ["steve\nstop", "steve\rstop", "steve\u{0}"] {
+            assert!(given_values(&configured, &given("player", value)).is_err());
+        }
+        assert_eq!(
+            given_values(&configured, &given("player", "steve")),
+            Ok(values(&[("player", "steve")]))
+        );
+    }
+
+    #[test]
+    fn regex_is_checked() {
+        let mut option = string_option("player");
+        option.regex = Some(String::from("^[a-z]+$").try_into().unwrap());
+        let configured = [option];
+        assert!(given_values(&configured, &given("player", "steve")).is_ok());
+        assert!(given_values(&configured, &given("player", "Steve!")).is_err());
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serenity::http::{HttpError, StatusCode};
+use serenity::model::prelude::ChannelId;
+use serenity::prelude::{Mutex, SerenityError};
+use tokio::sync::Notify;
+use tokio::time::sleep;
+
+use super::Handler;
+
+/// A discord message that couldn't be sent yet.
+#[derive(Clone, Debug)]
+pub struct PendingMessage {
+    pub channel: ChannelId,
+    pub msg: String,
+}
+
+/// Messages waiting for a retry, delivered in order by the outbox thread.
+#[derive(Default)]
+pub struct Outbox {
+    queue: Mutex<VecDeque<PendingMessage>>,
+    notify: Notify,
+}
+
+impl Outbox {
+    /// Upper limit for queued messages, the oldest ones are dropped first.
+    const MAX_QUEUED: usize = 100;
+
+    pub async fn is_empty(&self) -> bool {
+        self.queue.lock().await.is_empty()
+    }
+
+    pub async fn push(&self, pending: PendingMessage) {
+        let mut queue = self.queue.lock().await;
+        if queue.len() >= Self::MAX_QUEUED {
+            if let Some(dropped) = queue.pop_front() {
+                log::error!(
+                    "Outbox is full, dropping message for channel {}: {}",
+                    dropped.channel,
+                    dropped.msg
+                );
+            }
+        }
+        queue.push_back(pending);
+        self.notify.notify_one();
+    }
+}
+
+/// Whether a failed send might go through later: rate limits, server errors and network
+/// problems. Anything else, like an unknown channel or missing access, fails the same way
+/// on every retry.
+pub fn is_transient(err: &SerenityError) -> bool {
+    match err {
+        SerenityError::Http(err) => match err.as_ref() {
+            HttpError::UnsuccessfulRequest(response) => {
+                response.status_code == StatusCode::TOO_MANY_REQUESTS
+                    || response.status_code.is_server_error()
+            }
+            HttpError::Request(_) => true,
+            _ => false,
+        },
+        SerenityError::Io(_) => true,
+        _ => false,
+    }
+}
+
+impl Handler {
+    /// Attempts per message before it is dropped.
+    const MAX_SEND_ATTEMPTS: u32 = 8;
+    const MAX_SEND_BACKOFF: Duration = Duration::from_secs(60);
+
+    /// Retries the queued messages with an exponential backoff, oldest first. Messages
+    /// that fail for good are dropped right away, so they don't hold up the rest.
+    pub async fn start_outbox_thread(handler: Arc<Self>) {
+        log::debug!("Started outbox thread!");
+
+        loop {
+            handler.outbox.notify.notified().await;
+
+            let mut attempt = 0;
+            loop {
+                let pending = match handler.outbox.queue.lock().await.front() {
+                    Some(pending) => pending.clone(),
+                    None => break,
+                };
+
+                sleep(Duration::from_secs(1 << attempt.min(6)).min(Self::MAX_SEND_BACKOFF)).await;
+                attempt += 1;
+
+                let res = pending
+                    .channel
+                    .send_message(&handler.http, |m| m.content(&pending.msg))
+                    .await;
+
+                match res {
+                    Ok(_) => {
+                        log::debug!(
+                            "Delivered queued message to channel {} after {attempt} retries",
+                            pending.channel
+                        );
+                        handler.outbox.queue.lock().await.pop_front();
+                        attempt = 0;
+                    }
+                    Err(err) if !is_transient(&err) => {
+                        log::error!(
+                            "Dropping message for channel {}, it can't be delivered: {err}. Message: {}",
+                            pending.channel,
+                            pending.msg
+                        );
+                        handler.outbox.queue.lock().await.pop_front();
+                        attempt = 0;
+                    }
+                    Err(err) if attempt >= Self::MAX_SEND_ATTEMPTS => {
+                        log::error!(
+                            "Giving up on message for channel {} after {attempt} retries: {err}. Message: {}",
+                            pending.channel,
+                            pending.msg
+                        );
+                        handler.outbox.queue.lock().await.pop_front();
+                        attempt = 0;
+                    }
+                    Err(err) => log::warn!(
+                        "Retry {attempt} for channel {} failed: {err}",
+                        pending.channel
+                    ),
+                }
+            }
+        }
+    }
+}
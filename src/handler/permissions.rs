@@ -0,0 +1,165 @@
+use std::fmt::Display;
+
+use serenity::model::{
+    application::interaction::{
+        application_command::ApplicationCommandInteraction, autocomplete::AutocompleteInteraction,
+    },
+    guild::Member,
+    id::{ChannelId, GuildId, RoleId, UserId},
+    Permissions,
+};
+
+use crate::config::bot::SlashCommandConfig;
+use crate::instance::Instance;
+
+/// The caller of a slash command, reduced to what the permission checks need.
+pub struct Caller {
+    pub user_id: UserId,
+    pub channel_id: ChannelId,
+    pub guild_id: Option<GuildId>,
+    pub role_ids: Vec<RoleId>,
+    pub permissions: Permissions,
+}
+
+#[derive(Debug)]
+pub enum PermissionDenied {
+    Guild,
+    Channel(ChannelId),
+    User(UserId),
+    MissingPermissions(Permissions),
+}
+
+impl Caller {
+    fn new(
+        user_id: UserId,
+        channel_id: ChannelId,
+        guild_id: Option<GuildId>,
+        member: Option<&Member>,
+    ) -> Caller {
+        let (role_ids, permissions) = match member {
+            Some(member) => (
+                member.roles.clone(),
+                member.permissions.unwrap_or_else(Permissions::empty),
+            ),
+            None => (Vec::new(), Permissions::empty()),
+        };
+
+        Caller {
+            user_id,
+            channel_id,
+            guild_id,
+            role_ids,
+            permissions,
+        }
+    }
+
+    pub fn from_interaction(command: &ApplicationCommandInteraction) -> Caller {
+        Caller::new(
+            command.user.id,
+            command.channel_id,
+            command.guild_id,
+            command.member.as_ref(),
+        )
+    }
+
+    pub fn from_autocomplete(autocomplete: &AutocompleteInteraction) -> Caller {
+        Caller::new(
+            autocomplete.user.id,
+            autocomplete.channel_id,
+            autocomplete.guild_id,
+            autocomplete.member.as_ref(),
+        )
+    }
+}
+
+impl Display for PermissionDenied {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PermissionDenied::Guild => write!(f, "This instance isn't available in this server."),
+            PermissionDenied::Channel(channel) => write!(
+                f,
+                "Commands for this instance can't be used in <#{}>.",
+                channel.0
+            ),
+            PermissionDenied::User(user) => write!(
+                f,
+                "<@{}> isn't allowed to use this command for this instance.",
+                user.0
+            ),
+            PermissionDenied::MissingPermissions(missing) => write!(
+                f,
+                "This command requires the following permissions: {}.",
+                missing.get_permission_names().join(", ")
+            ),
+        }
+    }
+}
+
+/// Checks the restrictions of the given instance and command against the caller.
+///
+/// Missing or empty lists don't restrict anything, so an instance without
+/// `allowed-channel-ids` can be used from every channel of its server. Users and
+/// roles are alternatives, the caller passes if either of them matches. A command
+/// that sets its own user or role list replaces both lists of the instance.
+pub fn check(
+    instance: &Instance,
+    slash_cmd: &SlashCommandConfig,
+    caller: &Caller,
+) -> Result<(), PermissionDenied> {
+    let restrictions = &instance.restrictions;
+
+    if caller.guild_id != Some(GuildId(restrictions.server_id)) {
+        return Err(PermissionDenied::Guild);
+    }
+
+    if let Some(channel_ids) = &restrictions.allowed_channel_ids {
+        if !channel_ids.is_empty() && !channel_ids.contains(&caller.channel_id.0) {
+            return Err(PermissionDenied::Channel(caller.channel_id));
+        }
+    }
+
+    let (user_ids, role_ids) =
+        if slash_cmd.allowed_user_ids.is_some() || slash_cmd.allowed_role_ids.is_some() {
+            (&slash_cmd.allowed_user_ids, &slash_cmd.allowed_role_ids)
+        } else {
+            (
+                &restrictions.allowed_user_ids,
+                &restrictions.allowed_role_ids,
+            )
+        };
+    let user_ids = user_ids.as_deref().unwrap_or_default();
+    let role_ids = role_ids.as_deref().unwrap_or_default();
+
+    let unrestricted = user_ids.is_empty() && role_ids.is_empty();
+    let allowed = user_ids.contains(&caller.user_id.0)
+        || caller
+            .role_ids
+            .iter()
+            .any(|role| role_ids.contains(&role.0));
+
+    if !unrestricted && !allowed {
+        return Err(PermissionDenied::User(caller.user_id));
+    }
+
+    let missing = required_permissions(instance, slash_cmd) - caller.permissions;
+    if !missing.is_empty() {
+        return Err(PermissionDenied::MissingPermissions(missing));
+    }
+
+    Ok(())
+}
+
+/// Resolves the discord permissions a command requires. These are also published as
+/// `default_member_permissions`, so discord hides the command from members without them.
+/// User and role lists can't be published that way and are only checked on use.
+pub fn required_permissions(instance: &Instance, slash_cmd: &SlashCommandConfig) -> Permissions {
+    slash_cmd
+        .required_permissions
+        .as_ref()
+        .or(instance.restrictions.required_permissions.as_ref())
+        .into_iter()
+        .flatten()
+        .fold(Permissions::empty(), |permissions, permission| {
+            permissions | **permission
+        })
+}
@@ -0,0 +1,421 @@
+use std::collections::BTreeMap;
+
+use serenity::{
+    builder::{CreateApplicationCommand, CreateApplicationCommandOption},
+    http::Http,
+    json::Value,
+    model::{
+        application::command::{Command, CommandOption, CommandOptionType},
+        id::GuildId,
+        Permissions,
+    },
+};
+
+use super::{permissions, Handler};
+use crate::config::bot;
+
+/// Discord rejects more than 100 slash commands per guild.
+const MAX_GUILD_COMMANDS: usize = 100;
+
+/// A slash command as it should be registered in a guild.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CommandSpec {
+    pub name: String,
+    pub description: String,
+    // empty permissions aren't published, everyone can see the command then
+    pub default_member_permissions: Permissions,
+    pub options: Vec<OptionSpec>,
+}
+
+/// An option of a [`CommandSpec`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct OptionSpec {
+    pub kind: CommandOptionType,
+    pub name: String,
+    pub description: String,
+    pub required: bool,
+    pub autocomplete: bool,
+    pub choices: Vec<bot::ChoiceValue>,
+    pub min_value: Option<i64>,
+    pub max_value: Option<i64>,
+    pub min_length: Option<u16>,
+    pub max_length: Option<u16>,
+}
+
+/// What a registration run changed in a single guild.
+#[derive(Debug, Default)]
+pub struct RegistrationReport {
+    pub created: Vec<String>,
+    pub edited: Vec<String>,
+    pub deleted: Vec<String>,
+    pub unchanged: Vec<String>,
+    pub skipped: Vec<String>,
+    pub failed: Vec<(String, String)>,
+}
+
+impl CommandSpec {
+    fn build<'a>(
+        &self,
+        command: &'a mut CreateApplicationCommand,
+    ) -> &'a mut CreateApplicationCommand {
+        if self.default_member_permissions.is_empty() {
+            // explicitly reset, otherwise an edit keeps the previous permissions
+            command.0.insert("default_member_permissions", Value::Null);
+        } else {
+            command.default_member_permissions(self.default_member_permissions);
+        }
+
+        for option in &self.options {
+            command.create_option(|command_option| option.build(command_option));
+        }
+
+        command.name(&self.name).description(&self.description)
+    }
+
+    fn matches(&self, existing: &Command) -> bool {
+        self.name == existing.name
+            && self.description == existing.description
+            && self.default_member_permissions
+                == existing.default_member_permissions.unwrap_or_default()
+            && self.options.len() == existing.options.len()
+            && self
+                .options
+                .iter()
+                .zip(&existing.options)
+                .all(|(option, existing)| option.matches(existing))
+    }
+}
+
+impl OptionSpec {
+    /// The option every instance command starts with.
+    fn instance() -> OptionSpec {
+        OptionSpec {
+            kind: CommandOptionType::String,
+            name: Handler::INSTANCE_OPTION.to_string(),
+            description: String::from("The instance to run the command on"),
+            required: true,
+            autocomplete: true,
+            ..OptionSpec::optional(CommandOptionType::String, "", "")
+        }
+    }
+
+    /// An option declared in the config of a slash command.
+    fn configured(option: &bot::CommandOptionConfig) -> OptionSpec {
+        let kind = match option.kind {
+            bot::CommandOptionKind::String => CommandOptionType::String,
+            bot::CommandOptionKind::Integer => CommandOptionType::Integer,
+            bot::CommandOptionKind::Boolean => CommandOptionType::Boolean,
+            bot::CommandOptionKind::User => CommandOptionType::User,
+        };
+        let description = if option.description.is_empty() {
+            format!("The {}", option.name)
+        } else {
+            option.description.clone()
+        };
+
+        OptionSpec {
+            required: option.required,
+            choices: option.choices.clone(),
+            min_value: option.min,
+            max_value: option.max,
+            min_length: option.min_length,
+            max_length: option.max_length,
+            ..OptionSpec::optional(kind, &option.name, &description)
+        }
+    }
+
+    /// The `instance` option, followed by the options of reserved commands or the
+    /// configured ones, required ones first as discord demands.
+    fn for_command(slash_cmd_name: &str, slash_cmd: &bot::SlashCommandConfig) -> Vec<OptionSpec> {
+        let mut options = vec![OptionSpec::instance()];
+        if slash_cmd_name == "logs" {
+            options.extend([
+                OptionSpec::optional(
+                    CommandOptionType::Integer,
+                    Handler::LINES_OPTION,
+                    "How many lines to show, 20 by default",
+                ),
+                OptionSpec::optional(
+                    CommandOptionType::String,
+                    Handler::GREP_OPTION,
+                    "Only show lines matching this regex",
+                ),
+                OptionSpec::optional(
+                    CommandOptionType::String,
+                    Handler::SINCE_OPTION,
+                    "Only show lines since e.g. 10m, 2h or 14:02",
+                ),
+            ]);
+        } else if slash_cmd_name == "schedule" {
+            let mut action = OptionSpec::optional(
+                CommandOptionType::String,
+                Handler::SCHEDULE_ACTION_OPTION,
+                "list, pause or resume, list by default",
+            );
+            action.choices = ["list", "pause", "resume"]
+                .map(|choice| bot::ChoiceValue::String(choice.to_string()))
+                .into();
+            options.extend([
+                action,
+                OptionSpec::optional(
+                    CommandOptionType::String,
+                    Handler::SCHEDULE_OPTION,
+                    "The schedule to pause or resume",
+                ),
+            ]);
+        }
+
+        let mut configured: Vec<_> = slash_cmd
+            .options
+            .iter()
+            .filter(|option| option.name != Handler::INSTANCE_OPTION)
+            .map(OptionSpec::configured)
+            .collect();
+        configured.sort_by_key(|option| !option.required);
+        options.extend(configured);
+        options
+    }
+
+    fn optional(kind: CommandOptionType, name: &str, description: &str) -> OptionSpec {
+        OptionSpec {
+            kind,
+            name: name.to_string(),
+            description: description.to_string(),
+            required: false,
+            autocomplete: false,
+            choices: Vec::new(),
+            min_value: None,
+            max_value: None,
+            min_length: None,
+            max_length: None,
+        }
+    }
+
+    fn build<'a>(
+        &self,
+        option: &'a mut CreateApplicationCommandOption,
+    ) -> &'a mut CreateApplicationCommandOption {
+        option
+            .kind(self.kind)
+            .name(&self.name)
+            .description(&self.description)
+            .required(self.required)
+            .set_autocomplete(self.autocomplete);
+
+        for choice in &self.choices {
+            match choice {
+                bot::ChoiceValue::String(value) => option.add_string_choice(value, value),
+                bot::ChoiceValue::Integer(value) => option.add_int_choice(value, *value),
+            };
+        }
+        if let Some(min) = self.min_value {
+            option.min_int_value(min);
+        }
+        if let Some(max) = self.max_value {
+            option.max_int_value(max);
+        }
+        if let Some(min) = self.min_length {
+            option.min_length(min);
+        }
+        if let Some(max) = self.max_length {
+            option.max_length(max);
+        }
+        option
+    }
+
+    fn matches(&self, existing: &CommandOption) -> bool {
+        self.kind == existing.kind
+            && self.name == existing.name
+            && self.description == existing.description
+            && self.required == existing.required
+            && self.autocomplete == existing.autocomplete
+            && self.choices.len() == existing.choices.len()
+            && self
+                .choices
+                .iter()
+                .zip(&existing.choices)
+                .all(|(choice, existing)| {
+                    choice.to_string() == existing.name
+                        && match choice {
+                            bot::ChoiceValue::String(value) => {
+                                existing.value.as_str() == Some(value)
+                            }
+                            bot::ChoiceValue::Integer(value) => {
+                                existing.value.as_i64() == Some(i64::from(*value))
+                            }
+                        }
+                })
+            && self.min_value == existing.min_value.as_ref().and_then(|min| min.as_i64())
+            && self.max_value == existing.max_value.as_ref().and_then(|max| max.as_i64())
+            && self.min_length == existing.min_length
+            && self.max_length == existing.max_length
+    }
+}
+
+/// Collects the commands of all instances, grouped by the guild they are registered in.
+///
+/// Instances of a guild share one command per command name, the instance is picked
+/// through the autocompleted `instance` option. The published permissions are the ones
+/// all of these instances require, the stricter ones are enforced on use.
+pub fn guild_commands(cfg: &bot::Config) -> BTreeMap<GuildId, Vec<CommandSpec>> {
+    let mut guilds: BTreeMap<GuildId, Vec<CommandSpec>> = BTreeMap::new();
+
+    let mut instances: Vec<_> = cfg.instances.iter().collect();
+    instances.sort_by_key(|(instance_name, _)| *instance_name);
+
+    for (instance_name, instance) in instances {
+        let guild_id = GuildId(instance.restrictions.server_id);
+        let commands = guilds.entry(guild_id).or_default();
+
+        for (slash_cmd_name, slash_cmd) in &instance.slash_commands {
+            let required = permissions::required_permissions(instance, slash_cmd);
+
+            match commands
+                .iter_mut()
+                .find(|command| &command.name == slash_cmd_name)
+            {
+                Some(command) => {
+                    command.default_member_permissions &= required;
+                    if command.description != slash_cmd.description {
+                        log::debug!("[{instance_name}] Description of `/{slash_cmd_name}` differs from another instance, keeping the first one.");
+                    }
+                    if command.options != OptionSpec::for_command(slash_cmd_name, slash_cmd) {
+                        log::debug!("[{instance_name}] Options of `/{slash_cmd_name}` differ from another instance, keeping the first ones.");
+                    }
+                }
+                None => commands.push(CommandSpec {
+                    name: slash_cmd_name.clone(),
+                    description: if slash_cmd.description.is_empty() {
+                        format!("Runs `{slash_cmd_name}` on an instance")
+                    } else {
+                        slash_cmd.description.clone()
+                    },
+                    default_member_permissions: required,
+                    options: OptionSpec::for_command(slash_cmd_name, slash_cmd),
+                }),
+            }
+        }
+    }
+
+    for commands in guilds.values_mut() {
+        commands.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+
+    guilds
+}
+
+/// Brings the registered commands of a guild in line with the given commands.
+///
+/// Only the differences are sent to discord, so unchanged commands keep their id
+/// and the guild isn't hit with a bulk overwrite on every reconnect.
+pub async fn register_guild(
+    http: impl AsRef<Http>,
+    guild_id: GuildId,
+    commands: Vec<CommandSpec>,
+) -> RegistrationReport {
+    let http = http.as_ref();
+    let mut report = RegistrationReport::default();
+    let mut commands = commands;
+
+    if commands.len() > MAX_GUILD_COMMANDS {
+        report.skipped = commands
+            .split_off(MAX_GUILD_COMMANDS)
+            .into_iter()
+            .map(|command| command.name)
+            .collect();
+    }
+
+    let existing = match guild_id.get_application_commands(http).await {
+        Ok(existing) => existing,
+        Err(err) => {
+            report.failed.push((
+                String::from("*"),
+                format!("Couldn't fetch registered commands: {err}"),
+            ));
+            return report;
+        }
+    };
+
+    for registered in &existing {
+        if commands
+            .iter()
+            .any(|command| command.name == registered.name)
+        {
+            continue;
+        }
+
+        match guild_id
+            .delete_application_command(http, registered.id)
+            .await
+        {
+            Ok(()) => report.deleted.push(registered.name.clone()),
+            Err(err) => report
+                .failed
+                .push((registered.name.clone(), err.to_string())),
+        }
+    }
+
+    for command in commands {
+        let result = match existing
+            .iter()
+            .find(|registered| registered.name == command.name)
+        {
+            Some(registered) if command.matches(registered) => {
+                report.unchanged.push(command.name);
+                continue;
+            }
+            Some(registered) => guild_id
+                .edit_application_command(http, registered.id, |c| command.build(c))
+                .await
+                .map(|_| &mut report.edited),
+            None => guild_id
+                .create_application_command(http, |c| command.build(c))
+                .await
+                .map(|_| &mut report.created),
+        };
+
+        match result {
+            Ok(list) => list.push(command.name),
+            Err(err) => report.failed.push((command.name, err.to_string())),
+        }
+    }
+
+    report
+}
+
+impl RegistrationReport {
+    pub fn log(&self, guild_id: GuildId) {
+        log::info!(
+            "Registered commands for guild {guild_id}: {} created, {} edited, {} deleted, {} unchanged",
+            self.created.len(),
+            self.edited.len(),
+            self.deleted.len(),
+            self.unchanged.len()
+        );
+
+        for (label, names) in [
+            ("Created", &self.created),
+            ("Edited", &self.edited),
+            ("Deleted", &self.deleted),
+            ("Unchanged", &self.unchanged),
+        ] {
+            if !names.is_empty() {
+                log::debug!(
+                    "{label} commands for guild {guild_id}: {}",
+                    names.join(", ")
+                );
+            }
+        }
+
+        if !self.skipped.is_empty() {
+            log::error!(
+                "Guild {guild_id} exceeds the limit of {MAX_GUILD_COMMANDS} commands. Didn't register: {}",
+                self.skipped.join(", ")
+            );
+        }
+
+        for (name, err) in &self.failed {
+            log::error!("Couldn't register command `{name}` for guild {guild_id}: {err}");
+        }
+    }
+}
@@ -0,0 +1,492 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs, io,
+    str::FromStr,
+    sync::{Arc, PoisonError},
+};
+
+use chrono::{DateTime, Local};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use serenity::model::application::interaction::application_command::CommandDataOption;
+use serenity::model::prelude::ChannelId;
+use tokio::{sync::mpsc, time::sleep};
+
+use super::{options, Handler};
+use crate::config::bot::{self, ScheduleConfig, SlashCommandConfig};
+
+/// A schedule of an instance, parsed from its config.
+#[derive(Debug)]
+pub struct Schedule {
+    pub instance_name: String,
+    pub name: String,
+    pub cfg: ScheduleConfig,
+    cron: cron::Schedule,
+    timezone: Option<Tz>,
+}
+
+/// The names of the paused schedules of each instance, as kept in the schedules file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PausedSchedules {
+    #[serde(default)]
+    paused: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl Schedule {
+    /// Checks the cron expression, the timezone and the action of a schedule.
+    pub fn parse(
+        instance_name: &str,
+        name: &str,
+        cfg: &ScheduleConfig,
+        slash_cmds: &HashMap<String, SlashCommandConfig>,
+    ) -> Result<Schedule, String> {
+        let cron = cron_expression(&cfg.cron)
+            .and_then(|expression| {
+                cron::Schedule::from_str(&expression).map_err(|err| err.to_string())
+            })
+            .map_err(|err| format!("invalid cron `{}`: {err}", cfg.cron))?;
+        let timezone = cfg
+            .timezone
+            .as_deref()
+            .map(|timezone| {
+                Tz::from_str(timezone)
+                    .map_err(|err| format!("invalid timezone `{timezone}`: {err}"))
+            })
+            .transpose()?;
+
+        let action = cfg.action.as_str();
+        if action == "schedule" {
+            return Err(String::from("`schedule` can't be scheduled"));
+        }
+        if !Handler::RESERVED_COMMANDS.contains(&action) && !slash_cmds.contains_key(action) {
+            return Err(format!("there is no command `{action}`"));
+        }
+
+        Ok(Schedule {
+            instance_name: instance_name.to_string(),
+            name: name.to_string(),
+            cfg: cfg.clone(),
+            cron,
+            timezone,
+        })
+    }
+
+    /// The first time the schedule is due after `after`, `None` if it never is again.
+    fn next_run(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        match self.timezone {
+            Some(timezone) => self
+                .cron
+                .after(&after.with_timezone(&timezone))
+                .next()
+                .map(|at| at.with_timezone(&Local)),
+            None => self.cron.after(&after).next(),
+        }
+    }
+
+    /// The next run in the timezone of the schedule.
+    fn describe_next_run(&self) -> String {
+        let next_run = match self.timezone {
+            Some(timezone) => self
+                .cron
+                .upcoming(timezone)
+                .next()
+                .map(|at| at.format("%Y-%m-%d %H:%M %Z").to_string()),
+            None => self
+                .cron
+                .upcoming(Local)
+                .next()
+                .map(|at| at.format("%Y-%m-%d %H:%M").to_string()),
+        };
+        next_run.map_or_else(|| String::from("never"), |at| format!("next run {at}"))
+    }
+}
+
+impl PausedSchedules {
+    /// A missing or unreadable file pauses nothing.
+    pub fn load(path: &str) -> PausedSchedules {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return PausedSchedules::default(),
+            Err(err) => {
+                log::error!("Couldn't read paused schedules from `{path}`: {err}");
+                return PausedSchedules::default();
+            }
+        };
+
+        toml::from_str(&content).unwrap_or_else(|err| {
+            log::error!("Couldn't parse paused schedules in `{path}`: {err}");
+            PausedSchedules::default()
+        })
+    }
+
+    fn save(&self, path: &str) -> Result<(), String> {
+        let content = toml::to_string(self).map_err(|err| err.to_string())?;
+        fs::write(path, content).map_err(|err| err.to_string())
+    }
+
+    fn is_paused(&self, schedule: &Schedule) -> bool {
+        self.paused
+            .get(&schedule.instance_name)
+            .is_some_and(|paused| paused.contains(&schedule.name))
+    }
+
+    /// `false` if the schedule already was in that state.
+    fn set_paused(&mut self, schedule: &Schedule, paused: bool) -> bool {
+        let names = self
+            .paused
+            .entry(schedule.instance_name.clone())
+            .or_default();
+        let changed = if paused {
+            names.insert(schedule.name.clone())
+        } else {
+            names.remove(&schedule.name)
+        };
+        if names.is_empty() {
+            self.paused.remove(&schedule.instance_name);
+        }
+        changed
+    }
+}
+
+/// The schedules of all instances, sorted by instance and name. Errors name the first
+/// invalid one.
+pub fn schedules(cfg: &bot::Config) -> Result<Vec<Arc<Schedule>>, String> {
+    let mut schedules = cfg
+        .instances
+        .iter()
+        .flat_map(|(instance_name, instance)| {
+            instance.schedules.iter().map(move |(name, schedule)| {
+                Schedule::parse(instance_name, name, schedule, &instance.slash_commands)
+                    .map(Arc::new)
+                    .map_err(|err| format!("schedule `{name}` of `{instance_name}`: {err}"))
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    schedules.sort_by(|a, b| (&a.instance_name, &a.name).cmp(&(&b.instance_name, &b.name)));
+    Ok(schedules)
+}
+
+/// Turns a standard cron expression into one the cron crate reads. It wants seconds,
+/// five fields are the usual minute precision, and counts weekdays from 1 for Sunday
+/// where standard cron uses 0 or 7.
+fn cron_expression(cron: &str) -> Result<String, String> {
+    let mut fields: Vec<String> = cron.split_whitespace().map(str::to_string).collect();
+    if fields.len() == 5 {
+        fields.insert(0, String::from("0"));
+    }
+    // seconds, minute, hour, day, month, weekday and an optional year
+    if let Some(weekday) = fields.get_mut(5) {
+        *weekday = weekday_field(weekday)?;
+    }
+    Ok(fields.join(" "))
+}
+
+/// Spells out numeric weekdays as names, which both numberings agree on. Names are
+/// kept as they are.
+fn weekday_field(field: &str) -> Result<String, String> {
+    const NAMES: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    let number = |value: &str| match value.parse::<usize>() {
+        Ok(day) if day <= 7 => Ok(Some(day)),
+        Ok(_) => Err(format!("invalid weekday `{value}`")),
+        Err(_) => Ok(None),
+    };
+
+    let mut items = Vec::new();
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, Some(step)),
+            None => (item, None),
+        };
+        let bounds = match range.split_once('-') {
+            Some((first, last)) => number(first)?.zip(number(last)?),
+            None if range == "*" => step.map(|_| (0, 6)),
+            // `5/2` runs from Friday to the end of the week
+            None => number(range)?.map(|day| (day, if step.is_some() { 7 } else { day })),
+        };
+        let Some((first, last)) = bounds else {
+            items.push(item.to_string());
+            continue;
+        };
+
+        let step = match step {
+            Some(step) => step
+                .parse::<usize>()
+                .ok()
+                .filter(|step| *step > 0)
+                .ok_or_else(|| format!("invalid step `{step}`"))?,
+            None => 1,
+        };
+        if first > last {
+            return Err(format!("invalid weekday range `{range}`"));
+        }
+        items.extend(
+            (first..=last)
+                .step_by(step)
+                .map(|day| NAMES[day % 7].to_string()),
+        );
+    }
+    items.dedup();
+    Ok(items.join(","))
+}
+
+impl Handler {
+    pub const SCHEDULE_ACTION_OPTION: &'static str = "action";
+    pub const SCHEDULE_OPTION: &'static str = "schedule";
+
+    /// Parses the schedules of all instances, so an invalid one is caught when the
+    /// config is loaded.
+    pub fn check_schedules(cfg: &bot::Config) -> Result<(), String> {
+        schedules(cfg).map(|_| ())
+    }
+
+    /// Runs a schedule whenever it is due, until the bot shuts down.
+    pub async fn start_schedule_thread(handler: Arc<Handler>, schedule: Arc<Schedule>) {
+        let mut last_run = Local::now();
+
+        loop {
+            let Some(next_run) = schedule.next_run(last_run) else {
+                log::info!(
+                    "[{}] Schedule `{}` is never due again",
+                    schedule.instance_name,
+                    schedule.name
+                );
+                return;
+            };
+            sleep((next_run - Local::now()).to_std().unwrap_or_default()).await;
+            // an early wakeup must not make the same run due again
+            last_run = next_run.max(Local::now());
+
+            if !handler.is_accepting_commands() {
+                return;
+            }
+            let paused = handler
+                .paused_schedules
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .is_paused(&schedule);
+            if paused {
+                log::debug!(
+                    "[{}] Skipping paused schedule `{}`",
+                    schedule.instance_name,
+                    schedule.name
+                );
+                continue;
+            }
+
+            handler.run_schedule(&schedule).await;
+        }
+    }
+
+    /// Runs the action of a schedule and posts the outcome to the fallback channel.
+    async fn run_schedule(&self, schedule: &Schedule) {
+        let instance_name = &schedule.instance_name;
+        let instance = &self.cfg.instances[instance_name];
+        let action = schedule.cfg.action.as_str();
+        let channel = ChannelId(instance.restrictions.fallback_channel_id);
+        log::info!(
+            "[{instance_name}] Running schedule `{}`: `/{action}`",
+            schedule.name
+        );
+
+        // nobody watches the progress of a scheduled command
+        let (progress, _) = mpsc::channel(1);
+        let response = match instance.slash_commands.get(action) {
+            Some(slash_cmd) if !Self::RESERVED_COMMANDS.contains(&action) => {
+                match options::given_values(&slash_cmd.options, &schedule.cfg.options) {
+                    Ok(values) => {
+                        self.run_custom_command(
+                            instance_name,
+                            instance,
+                            action,
+                            slash_cmd,
+                            values,
+                            channel,
+                            &progress,
+                        )
+                        .await
+                    }
+                    Err(err) => Err(err),
+                }
+            }
+            slash_cmd => {
+                let default = SlashCommandConfig::default();
+                self.run_command(
+                    instance_name,
+                    instance,
+                    action,
+                    slash_cmd.unwrap_or(&default),
+                    &[],
+                    channel,
+                    &progress,
+                )
+                .await
+            }
+        };
+
+        let label = format!("Schedule `{}` of `{instance_name}`", schedule.name);
+        match response {
+            Ok(response) => {
+                for (index, msg) in response.messages.into_iter().enumerate() {
+                    let msg = if index == 0 {
+                        format!("{label}: {msg}")
+                    } else {
+                        msg
+                    };
+                    self.send_discord_message(channel, msg).await;
+                }
+            }
+            Err(err) => {
+                log::warn!(
+                    "[{instance_name}] Schedule `{}` failed: {err}",
+                    schedule.name
+                );
+                self.send_discord_message(channel, format!("{label} failed: {err}"))
+                    .await;
+            }
+        }
+    }
+
+    /// Answers `/schedule` by listing the schedules of an instance, or pausing or
+    /// resuming one of them.
+    pub fn schedule_command(
+        &self,
+        instance_name: &str,
+        options: &[CommandDataOption],
+    ) -> Result<String, String> {
+        let option = |name: &str| {
+            options
+                .iter()
+                .find(|option| option.name == name)
+                .and_then(|option| option.value.as_ref())
+                .and_then(|value| value.as_str())
+        };
+        let schedules: Vec<_> = self
+            .schedules
+            .iter()
+            .filter(|schedule| schedule.instance_name == instance_name)
+            .collect();
+        let mut paused_schedules = self
+            .paused_schedules
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        let pause = match option(Self::SCHEDULE_ACTION_OPTION).unwrap_or("list") {
+            "list" if schedules.is_empty() => {
+                return Ok(format!("`{instance_name}` has no schedules."))
+            }
+            "list" => {
+                let lines: Vec<_> = schedules
+                    .iter()
+                    .map(|schedule| {
+                        let timezone = schedule.cfg.timezone.as_deref().unwrap_or("local time");
+                        let state = if paused_schedules.is_paused(schedule) {
+                            String::from("paused")
+                        } else {
+                            schedule.describe_next_run()
+                        };
+                        format!(
+                            "`{}`: `/{}` at `{}` ({timezone}), {state}",
+                            schedule.name, schedule.cfg.action, schedule.cfg.cron
+                        )
+                    })
+                    .collect();
+                return Ok(format!(
+                    "Schedules of `{instance_name}`:\n{}",
+                    lines.join("\n")
+                ));
+            }
+            "pause" => true,
+            "resume" => false,
+            action => {
+                return Err(format!(
+                    "Unknown action `{action}`, use list, pause or resume."
+                ))
+            }
+        };
+
+        let name = option(Self::SCHEDULE_OPTION)
+            .ok_or_else(|| format!("Missing the `{}` option.", Self::SCHEDULE_OPTION))?;
+        let schedule = schedules
+            .iter()
+            .find(|schedule| schedule.name == name)
+            .ok_or_else(|| format!("`{instance_name}` has no schedule `{name}`."))?;
+
+        if !paused_schedules.set_paused(schedule, pause) {
+            let state = if pause { "already is" } else { "isn't" };
+            return Ok(format!(
+                "Schedule `{name}` of `{instance_name}` {state} paused."
+            ));
+        }
+        let verb = if pause { "Paused" } else { "Resumed" };
+        log::info!("[{instance_name}] {verb} schedule `{name}`");
+
+        match paused_schedules.save(&self.cfg.schedules_path) {
+            Ok(()) => Ok(format!("{verb} schedule `{name}` of `{instance_name}`.")),
+            Err(err) => {
+                log::error!(
+                    "Couldn't save paused schedules to `{}`: {err}",
+                    self.cfg.schedules_path
+                );
+                Ok(format!(
+                    "{verb} schedule `{name}` of `{instance_name}`, but couldn't save it, it is forgotten on a restart."
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Datelike, TimeZone, Weekday};
+
+    use super::*;
+
+    fn weekdays(cron: &str) -> Vec<Weekday> {
+        let cron = cron::Schedule::from_str(&cron_expression(cron).unwrap()).unwrap();
+        // a monday
+        let start = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let mut days: Vec<_> = cron.after(&start).take(7).map(|at| at.weekday()).collect();
+        days.sort_by_key(Weekday::num_days_from_sunday);
+        days.dedup();
+        days
+    }
+
+    #[test]
+    fn five_fields_get_seconds() {
+        assert_eq!(
+            cron_expression("0 4 * * *"),
+            Ok(String::from("0 0 4 * * *"))
+        );
+    }
+
+    #[test]
+    fn weekdays_count_from_sunday_as_zero() {
+        use Weekday::*;
+        assert_eq!(weekdays("0 4 * * 1-5"), [Mon, Tue, Wed, Thu, Fri]);
+        assert_eq!(weekdays("0 4 * * 0"), [Sun]);
+        assert_eq!(weekdays("0 4 * * 7"), [Sun]);
+        assert_eq!(weekdays("0 4 * * 5-7"), [Sun, Fri, Sat]);
+        assert_eq!(weekdays("0 4 * * */2"), [Sun, Tue, Thu, Sat]);
+        assert_eq!(weekdays("0 4 * * 1,3"), [Mon, Wed]);
+        assert_eq!(weekdays("0 0 4 * * 6"), [Sat]);
+    }
+
+    #[test]
+    fn weekday_names_are_kept() {
+        assert_eq!(
+            cron_expression("0 20 * * Sun"),
+            Ok(String::from("0 0 20 * * Sun"))
+        );
+        assert_eq!(
+            cron_expression("0 20 * * Mon-Fri"),
+            Ok(String::from("0 0 20 * * Mon-Fri"))
+        );
+    }
+
+    #[test]
+    fn invalid_weekdays_are_rejected() {
+        assert!(cron_expression("0 4 * * 8").is_err());
+        assert!(cron_expression("0 4 * * 5-1").is_err());
+        assert!(cron_expression("0 4 * * 1/0").is_err());
+    }
+}
@@ -0,0 +1,123 @@
+use std::fmt::Display;
+
+use chrono::{DateTime, Local};
+
+/// Lifecycle of an instance as seen by the handler.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InstanceState {
+    Stopped,
+    Starting,
+    Running,
+    // running, but failing its health check
+    Unhealthy,
+    Stopping,
+    Crashed,
+    Restarting,
+    // gave up restarting after a crash loop
+    Failed,
+}
+
+/// The current state of an instance and since when it is in it.
+#[derive(Clone, Debug)]
+pub struct InstanceStatus {
+    pub state: InstanceState,
+    pub since: DateTime<Local>,
+    pub last_exit: Option<String>,
+}
+
+impl Display for InstanceState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = match self {
+            InstanceState::Stopped => "stopped",
+            InstanceState::Starting => "starting",
+            InstanceState::Running => "running",
+            InstanceState::Unhealthy => "unhealthy",
+            InstanceState::Stopping => "stopping",
+            InstanceState::Crashed => "crashed",
+            InstanceState::Restarting => "restarting",
+            InstanceState::Failed => "failed",
+        };
+        write!(f, "{state}")
+    }
+}
+
+impl Default for InstanceStatus {
+    fn default() -> Self {
+        InstanceStatus::new(InstanceState::Stopped)
+    }
+}
+
+impl InstanceStatus {
+    pub fn new(state: InstanceState) -> InstanceStatus {
+        InstanceStatus {
+            state,
+            since: Local::now(),
+            last_exit: None,
+        }
+    }
+
+    pub fn transition(&mut self, instance_name: &str, state: InstanceState) {
+        if self.state != state {
+            log::debug!("[{instance_name}] State changed: {} -> {state}", self.state);
+            self.state = state;
+            self.since = Local::now();
+        }
+    }
+
+    /// `true` as long as there is a child process or one is about to be spawned.
+    pub fn is_active(&self) -> bool {
+        !matches!(
+            self.state,
+            InstanceState::Stopped | InstanceState::Crashed | InstanceState::Failed
+        )
+    }
+
+    /// Checks if a new child can be started in the current state.
+    pub fn validate_start(&self, instance_name: &str) -> Result<(), String> {
+        if self.is_active() {
+            Err(format!("`{instance_name}` is already {self}."))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks if the child can receive commands in the current state.
+    pub fn validate_running(&self, instance_name: &str) -> Result<(), String> {
+        match self.state {
+            InstanceState::Starting | InstanceState::Running | InstanceState::Unhealthy => Ok(()),
+            _ => Err(format!("`{instance_name}` isn't running, it is {self}.")),
+        }
+    }
+
+    /// Checks if the child can be restarted in the current state, a pending restart
+    /// skips the rest of its backoff.
+    pub fn validate_restart(&self, instance_name: &str) -> Result<(), String> {
+        match self.state {
+            InstanceState::Restarting => Ok(()),
+            _ => self.validate_running(instance_name),
+        }
+    }
+
+    /// Checks if there is a child that could be killed in the current state.
+    pub fn validate_active(&self, instance_name: &str) -> Result<(), String> {
+        if self.is_active() {
+            Ok(())
+        } else {
+            Err(format!("`{instance_name}` isn't running, it is {self}."))
+        }
+    }
+
+    fn since_display(&self) -> String {
+        if self.since.date_naive() == Local::now().date_naive() {
+            self.since.format("%H:%M").to_string()
+        } else {
+            self.since.format("%Y-%m-%d %H:%M").to_string()
+        }
+    }
+}
+
+impl Display for InstanceStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} since {}", self.state, self.since_display())
+    }
+}
@@ -0,0 +1,439 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, PoisonError,
+    },
+    time::Duration,
+};
+
+use serenity::model::prelude::ChannelId;
+use tokio::{
+    sync::{mpsc::Sender, oneshot},
+    time::{sleep, timeout},
+};
+
+use super::commands::CommandResponse;
+use super::logs::MAX_MESSAGE_LENGTH;
+use super::state::{InstanceState, InstanceStatus};
+use super::{options, Handler};
+use crate::config::bot::{WorkflowAction, WorkflowStep};
+use crate::instance::{
+    crash::describe_exit,
+    exec::{ExecEvent, ExecRun},
+    InstanceInEvents,
+};
+
+/// The workflow running on an instance, there is at most one per instance.
+#[derive(Debug)]
+pub struct RunningWorkflow {
+    pub name: String,
+    // tells runs apart, a cancelled run might end after the next one started
+    id: u64,
+    cancel: oneshot::Sender<()>,
+}
+
+/// Forgets the workflow of an instance once it ended, however it ended.
+struct WorkflowGuard<'a> {
+    workflows: &'a Mutex<HashMap<String, RunningWorkflow>>,
+    instance_name: &'a str,
+    id: u64,
+}
+
+/// The state a workflow carries from one step to the next.
+struct WorkflowRun {
+    instance_name: String,
+    label: String,
+    values: HashMap<String, String>,
+    channel: ChannelId,
+    // log buffer position wait-for steps start reading from
+    cursor: u64,
+    // what happened so far, one line per step
+    report: Vec<String>,
+}
+
+type StepsFuture<'a> = Pin<Box<dyn Future<Output = Result<(), String>> + Send + 'a>>;
+
+impl Drop for WorkflowGuard<'_> {
+    fn drop(&mut self) {
+        let mut workflows = self
+            .workflows
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if workflows
+            .get(self.instance_name)
+            .is_some_and(|running| running.id == self.id)
+        {
+            workflows.remove(self.instance_name);
+        }
+    }
+}
+
+impl Handler {
+    /// Output quoted in the report of a failed step is cut after this many characters.
+    const MAX_REPORTED_LINE: usize = 200;
+
+    /// Runs the steps of a workflow one after another, reporting each of them as its
+    /// progress. Ends at the first failed step or once the workflow is cancelled.
+    pub async fn run_workflow(
+        &self,
+        instance_name: &str,
+        slash_cmd_name: &str,
+        steps: &[WorkflowStep],
+        values: HashMap<String, String>,
+        channel: ChannelId,
+        progress: &Sender<String>,
+    ) -> Result<CommandResponse, String> {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let (cancel, cancelled) = oneshot::channel();
+        {
+            let mut workflows = self
+                .workflows
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            // checked under the lock, the shutdown takes it to cancel all workflows
+            if !self.is_accepting_commands() {
+                return Err(String::from(
+                    "macobot is shutting down and doesn't run workflows anymore.",
+                ));
+            }
+            if let Some(running) = workflows.get(instance_name) {
+                return Err(format!(
+                    "`/{}` is still running on `{instance_name}`, `/cancel` it first.",
+                    running.name
+                ));
+            }
+            workflows.insert(
+                instance_name.to_string(),
+                RunningWorkflow {
+                    name: slash_cmd_name.to_string(),
+                    id,
+                    cancel,
+                },
+            );
+        }
+        let _running = WorkflowGuard {
+            workflows: &self.workflows,
+            instance_name,
+            id,
+        };
+
+        log::info!("[{instance_name}] Running workflow `/{slash_cmd_name}`");
+        let mut run = WorkflowRun {
+            instance_name: instance_name.to_string(),
+            label: format!("`/{slash_cmd_name}` on `{instance_name}`"),
+            values,
+            channel,
+            cursor: self.log_position(instance_name),
+            report: Vec::new(),
+        };
+
+        let outcome = tokio::select! {
+            outcome = self.run_steps(&mut run, steps, progress, "") => outcome,
+            _ = cancelled => Err(String::from("was cancelled")),
+        };
+
+        let header = match outcome {
+            Ok(()) => format!("{} finished.", run.label),
+            Err(reason) => {
+                log::info!("[{instance_name}] Workflow `/{slash_cmd_name}` {reason}");
+                format!("{} {reason}.", run.label)
+            }
+        };
+        Ok(fit_report(header, &run.report).into())
+    }
+
+    /// Answers `/cancel` by ending the workflow running on an instance.
+    pub fn cancel_workflow(&self, instance_name: &str) -> Result<String, String> {
+        let running = self
+            .workflows
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(instance_name)
+            .ok_or_else(|| format!("There is no workflow running on `{instance_name}`."))?;
+
+        // the workflow might have just ended on its own
+        let _ = running.cancel.send(());
+        Ok(format!(
+            "Cancelled `/{}` on `{instance_name}`.",
+            running.name
+        ))
+    }
+
+    /// Ends every running workflow, so none of them starts an instance during the shutdown.
+    pub fn cancel_workflows(&self) {
+        let workflows = std::mem::take(
+            &mut *self
+                .workflows
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+        for (instance_name, running) in workflows {
+            log::info!("[{instance_name}] Cancelling workflow `/{}`", running.name);
+            let _ = running.cancel.send(());
+        }
+    }
+
+    /// Boxed, as failing steps run their `on-failure` steps recursively.
+    fn run_steps<'a>(
+        &'a self,
+        run: &'a mut WorkflowRun,
+        steps: &'a [WorkflowStep],
+        progress: &'a Sender<String>,
+        prefix: &'a str,
+    ) -> StepsFuture<'a> {
+        Box::pin(async move {
+            for (index, step) in steps.iter().enumerate() {
+                let step_label = format!(
+                    "{prefix}{}/{}: {}",
+                    index + 1,
+                    steps.len(),
+                    describe(&step.action)
+                );
+                let current = format!("Running {}, {step_label}", run.label);
+                // a closed channel just means nobody watches the progress
+                let _ = progress.send(fit_report(current, &run.report)).await;
+
+                let outcome = match step.action {
+                    WorkflowAction::Sleep { .. } => self.run_step(run, &step.action).await,
+                    _ => timeout(
+                        Duration::from_secs(step.timeout),
+                        self.run_step(run, &step.action),
+                    )
+                    .await
+                    .unwrap_or_else(|_| Err(format!("timed out after {}s", step.timeout))),
+                };
+
+                match outcome {
+                    Ok(()) => run.report.push(format!("{step_label}: done")),
+                    Err(reason) => {
+                        run.report.push(format!("{step_label}: failed, {reason}"));
+                        if !step.on_failure.is_empty() {
+                            let prefix = format!("{prefix}on failure of {}, ", index + 1);
+                            // the original failure is what the workflow ends with
+                            let _ = self
+                                .run_steps(run, &step.on_failure, progress, &prefix)
+                                .await;
+                        }
+                        return Err(format!("failed at step {}: {reason}", index + 1));
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    async fn run_step(&self, run: &mut WorkflowRun, action: &WorkflowAction) -> Result<(), String> {
+        match action {
+            WorkflowAction::Stdin { cmd } => {
+                let sender = self.running_sender(&run.instance_name).await?;
+                run.cursor = self.log_position(&run.instance_name);
+                sender
+                    .send(InstanceInEvents::ExecuteStdinCommand(options::render(
+                        cmd,
+                        &run.values,
+                    )))
+                    .await
+                    .map_err(|err| err.to_string())
+            }
+            WorkflowAction::WaitFor { pattern } => {
+                let logs = &self.logs[&run.instance_name];
+                let mut pushed = logs
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .subscribe();
+                loop {
+                    let (lines, _, next_position) = logs
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .read_from(run.cursor);
+                    let first_position = next_position - lines.len() as u64;
+                    if let Some(index) = lines.iter().position(|line| pattern.is_match(&line.text))
+                    {
+                        // a following wait-for continues after the matched line
+                        run.cursor = first_position + index as u64 + 1;
+                        return Ok(());
+                    }
+                    run.cursor = next_position;
+                    pushed
+                        .changed()
+                        .await
+                        .map_err(|_| String::from("lost the output of the instance"))?;
+                }
+            }
+            WorkflowAction::Sleep { seconds } => {
+                sleep(Duration::from_secs(*seconds)).await;
+                Ok(())
+            }
+            WorkflowAction::Exec { cmd_path, cmd_args } => {
+                let args: Vec<String> = cmd_args
+                    .iter()
+                    .map(|arg| options::render(arg, &run.values))
+                    .collect();
+                let instance = &self.cfg.instances[&run.instance_name];
+                let mut exec = ExecRun::spawn(instance, cmd_path, &args)?;
+
+                let mut last_line = None;
+                loop {
+                    match exec.next_event().await {
+                        ExecEvent::Line(line) => last_line = Some(line.text),
+                        ExecEvent::Exited(status) => {
+                            let status = status.map_err(|err| err.to_string())?;
+                            if status.success() {
+                                return Ok(());
+                            }
+                            let last_line = last_line
+                                .map(|line| {
+                                    let line: String =
+                                        line.chars().take(Self::MAX_REPORTED_LINE).collect();
+                                    format!(", last output: `{line}`")
+                                })
+                                .unwrap_or_default();
+                            return Err(format!(
+                                "exited with {}{last_line}",
+                                describe_exit(&status)
+                            ));
+                        }
+                    }
+                }
+            }
+            WorkflowAction::Start { instance } => {
+                let instance_name = self.step_target(run, instance)?;
+                let status = self.instance_status_of(instance_name).await;
+                if !status.is_active() {
+                    let instance = &self.cfg.instances[instance_name];
+                    self.start_instance(instance_name, instance, run.channel)
+                        .await?;
+                }
+                self.wait_for_status(instance_name, |status| match status.state {
+                    InstanceState::Running | InstanceState::Unhealthy => Some(Ok(())),
+                    InstanceState::Stopped | InstanceState::Crashed | InstanceState::Failed => {
+                        Some(Err(format!("`{instance_name}` is {status}")))
+                    }
+                    _ => None,
+                })
+                .await
+            }
+            WorkflowAction::Stop { instance } => {
+                let instance_name = self.step_target(run, instance)?;
+                if self.instance_status_of(instance_name).await.is_active() {
+                    self.send_lifecycle_event(
+                        instance_name,
+                        InstanceInEvents::Stop,
+                        InstanceState::Stopping,
+                        InstanceStatus::validate_active,
+                    )
+                    .await?;
+                }
+                self.wait_for_status(instance_name, |status| {
+                    (!status.is_active()).then_some(Ok(()))
+                })
+                .await
+            }
+            WorkflowAction::WaitForExit { instance } => {
+                let instance_name = self.step_target(run, instance)?;
+                self.wait_for_status(instance_name, |status| {
+                    (!status.is_active()).then_some(Ok(()))
+                })
+                .await
+            }
+            WorkflowAction::Message { text } => {
+                self.send_discord_message(run.channel, options::render(text, &run.values))
+                    .await;
+                Ok(())
+            }
+        }
+    }
+
+    /// The instance a step acts on, its own one without an `instance`.
+    fn step_target<'a>(
+        &'a self,
+        run: &'a WorkflowRun,
+        instance: &'a Option<String>,
+    ) -> Result<&'a str, String> {
+        match instance {
+            Some(instance_name) if self.cfg.instances.contains_key(instance_name) => {
+                Ok(instance_name)
+            }
+            Some(instance_name) => Err(format!("there is no instance named `{instance_name}`")),
+            None => Ok(&run.instance_name),
+        }
+    }
+
+    async fn instance_status_of(&self, instance_name: &str) -> InstanceStatus {
+        self.states
+            .lock()
+            .await
+            .get(instance_name)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Waits until `done` decides on the status of an instance.
+    async fn wait_for_status(
+        &self,
+        instance_name: &str,
+        done: impl Fn(&InstanceStatus) -> Option<Result<(), String>>,
+    ) -> Result<(), String> {
+        let mut changed = self.state_changed.subscribe();
+        loop {
+            if let Some(outcome) = done(&self.instance_status_of(instance_name).await) {
+                return outcome;
+            }
+            // the handler outlives the workflow, the sender can't be gone
+            if changed.changed().await.is_err() {
+                return Err(String::from("lost the state of the instance"));
+            }
+        }
+    }
+
+    fn log_position(&self, instance_name: &str) -> u64 {
+        self.logs[instance_name]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .position()
+    }
+}
+
+fn describe(action: &WorkflowAction) -> String {
+    let target = |instance: &Option<String>| match instance {
+        Some(instance_name) => format!("`{instance_name}`"),
+        None => String::from("the instance"),
+    };
+
+    match action {
+        WorkflowAction::Stdin { cmd } => format!("write `{cmd}`"),
+        WorkflowAction::WaitFor { pattern } => format!("wait for `{}`", pattern.as_str()),
+        WorkflowAction::Sleep { seconds } => format!("sleep {seconds}s"),
+        WorkflowAction::Exec { cmd_path, .. } => format!("run `{cmd_path}`"),
+        WorkflowAction::Start { instance } => format!("start {}", target(instance)),
+        WorkflowAction::Stop { instance } => format!("stop {}", target(instance)),
+        WorkflowAction::WaitForExit { instance } => {
+            format!("wait for {} to exit", target(instance))
+        }
+        WorkflowAction::Message { .. } => String::from("post a message"),
+    }
+}
+
+/// `header` followed by as many of the last report lines as fit into a message.
+fn fit_report(header: String, report: &[String]) -> String {
+    let mut length = header.len();
+    let kept = report
+        .iter()
+        .rev()
+        .take_while(|line| {
+            length += 1 + line.len();
+            length <= MAX_MESSAGE_LENGTH
+        })
+        .count();
+
+    let mut message = header;
+    for line in &report[report.len() - kept..] {
+        message.push('\n');
+        message.push_str(line);
+    }
+    message
+}
@@ -1,24 +1,52 @@
+pub mod crash;
+pub mod env;
+pub mod exec;
+pub mod health;
+pub mod logs;
+pub mod startup;
+pub mod watchdog;
+
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     fmt::Display,
-    io::{Read, Write},
-    path::Path,
-    process::{Child, Command, Stdio},
-    str,
-    sync::Arc,
+    future, io,
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{Command as StdCommand, ExitStatus, Stdio},
+    sync::PoisonError,
     time::{Duration, Instant},
 };
 
+use chrono::{DateTime, Local};
+use nix::{
+    sys::signal::{killpg, Signal},
+    unistd::Pid,
+};
 use serde::{Deserialize, Serialize};
-use serenity::prelude::Mutex;
 use tokio::{
-    sync::mpsc::{self, Receiver, Sender},
-    time::sleep,
+    io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader},
+    process::{Child, ChildStderr, ChildStdin, ChildStdout, Command},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        oneshot,
+    },
+    time::{sleep, timeout},
 };
 
-use crate::config::bot::{RestrictionConfig, SlashCommandConfig, StartupConfig};
+use crate::config::bot::{
+    HealthCheckConfig, LogStreamConfig, RestartPolicy, RestartPolicyConfig, RestrictionConfig,
+    ScheduleConfig, ShutdownConfig, SlashCommandConfig, StartupConfig, StderrMode, WatchdogConfig,
+    WatchdogRecovery,
+};
 use crate::handler::HandlerEvents;
 
+use self::crash::{describe_exit, CrashOutcome, CrashReport};
+use self::env::{EnvVars, InheritEnv};
+use self::health::{HealthChange, HealthCheckResult, HealthMonitor};
+use self::logs::SharedLogBuffer;
+use self::startup::StartupPatterns;
+use self::watchdog::{Watchdog, WatchdogEvent};
+
 // todo: separate Instance config struct and struct here... shouldn't be the same
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 #[serde(rename_all(deserialize = "kebab-case"))]
@@ -26,29 +54,179 @@ pub struct Instance {
     pub cmd_exec_dir: Option<String>,
     pub cmd_path: String,
     pub cmd_args: Option<Vec<String>>,
+    #[serde(default)]
+    pub stderr: StderrMode,
+    // output lines kept for `/logs`
+    #[serde(default = "Instance::default_log_lines")]
+    pub log_lines: usize,
+    // output lines shown in the crash report, the whole run is attached
+    #[serde(default = "Instance::default_crash_report_lines")]
+    pub crash_report_lines: usize,
+    #[serde(default)]
+    pub env: EnvVars,
+    #[serde(default)]
+    pub env_files: Vec<String>,
+    #[serde(default)]
+    pub inherit_env: InheritEnv,
     pub startup: StartupConfig,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    #[serde(default)]
+    pub restart_policy: RestartPolicyConfig,
+    #[serde(default)]
+    pub health_check: Option<HealthCheckConfig>,
+    #[serde(default)]
+    pub watchdog: Option<WatchdogConfig>,
+    #[serde(default)]
+    pub log_stream: Option<LogStreamConfig>,
     pub restrictions: RestrictionConfig,
     pub slash_commands: HashMap<String, SlashCommandConfig>,
+    // keyed by the name of the schedule
+    #[serde(default)]
+    pub schedules: HashMap<String, ScheduleConfig>,
 }
 
 #[derive(Debug)]
 pub enum InstanceInEvents {
     ExecuteStdinCommand(String),
+    Stop,
+    Restart,
+    Kill,
+    Status(oneshot::Sender<RunnerStatus>),
 }
 
 #[derive(Debug)]
 pub enum InstanceOutEvents {
-    ChangeDirFailure,
-    Stopped(String),
-    StoppedWithError(String),
-    StdoutInitializingFailure,
-    StartupTimeoutFinished(String),
-    ExecuteStdinCommandFailure(String),
+    ExecDirFailure(String, String),
+    SpawnFailure(String, String),
+    // pid and the log buffer position of the first line of the run
+    Started(String, u32, u64),
+    Stopped(String, String),
+    StoppedWithError(String, String),
+    // replaces the stopped, restarting or restarts exhausted event of the crash
+    Crashed(String, CrashReport),
+    StdoutInitializingFailure(String),
+    // matched_line is `None` if the startup timer or the health check decided
+    Ready {
+        name: String,
+        after: Duration,
+        matched_line: Option<String>,
+    },
+    // name, reason
+    StartupFailed(String, String),
+    // name, reason, restarting
+    Unhealthy(String, String, bool),
+    Healthy(String),
+    // name, reason, recovery
+    Hung(String, String, WatchdogRecovery),
+    Responsive(String),
+    ShutdownStep(String, String),
+    // name, exit status, attempt, delay
+    Restarting(String, String, u32, Duration),
+    // name, exit status, attempts
+    RestartsExhausted(String, String, u32),
+    ExecuteStdinCommandFailure(String, String),
+}
+
+/// Details only the runner of a child knows.
+#[derive(Debug)]
+pub struct RunnerStatus {
+    pub pid: u32,
+    pub started_at: DateTime<Local>,
+    pub last_exit: Option<String>,
+    // oldest first, empty without a health check
+    pub health_checks: Vec<HealthCheckResult>,
 }
 
 pub struct InstanceRunner {
     name: String,
     instance: Instance,
+    logs: SharedLogBuffer,
+}
+
+/// The pipe a line of output was read from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// A line the child wrote, without the line break.
+#[derive(Clone, Debug)]
+pub struct OutputLine {
+    pub stream: Stream,
+    pub text: String,
+}
+
+/// A spawned child and the pipes the runner talks to it through.
+struct ChildRun {
+    child: Child,
+    pid: u32,
+    stdin: Option<ChildStdin>,
+    output: ChildOutput,
+    started_at: DateTime<Local>,
+    // log buffer position of the first line of this run
+    log_position: u64,
+}
+
+/// Both output pipes of a child, read concurrently so neither can fill up.
+struct ChildOutput {
+    stdout: Option<OutputReader<ChildStdout>>,
+    stderr: Option<OutputReader<ChildStderr>>,
+    merge_stderr: bool,
+}
+
+/// Line-buffered reader over a pipe of the child, tolerating invalid utf-8.
+struct OutputReader<R> {
+    reader: BufReader<R>,
+    buf: Vec<u8>,
+}
+
+/// Why [`Instance::command`] couldn't set up a command.
+#[derive(Debug)]
+pub enum CommandSetupError {
+    ExecDir(String),
+    Env(String),
+}
+
+impl Display for CommandSetupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandSetupError::ExecDir(err) => write!(f, "invalid exec dir: {err}"),
+            CommandSetupError::Env(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+/// Why a run of the child ended.
+enum RunEnd {
+    Exited(ExitStatus),
+    Stopped(ExitStatus),
+    Restart(ExitStatus),
+    // restarted by a health check or the watchdog, counts towards a crash loop
+    Recover(ExitStatus),
+    // a failure pattern matched or the startup timed out
+    StartupFailed(String),
+    // the child couldn't be waited for, whether it still runs is unknown
+    WaitFailed(io::Error),
+}
+
+/// How waiting for the child to exit ended.
+enum ExitWait {
+    Exited(ExitStatus),
+    TimedOut,
+    // `kill` was requested while waiting
+    KillRequested,
+    Failed(io::Error),
+}
+
+impl Display for Stream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stream::Stdout => write!(f, "stdout"),
+            Stream::Stderr => write!(f, "stderr"),
+        }
+    }
 }
 
 impl Display for Instance {
@@ -60,23 +238,95 @@ impl Display for Instance {
     }
 }
 
+impl Instance {
+    fn default_log_lines() -> usize {
+        1000
+    }
+
+    fn default_crash_report_lines() -> usize {
+        20
+    }
+
+    /// A command that runs like the instance itself: in its `cmd-exec-dir` and with
+    /// its environment, without touching the cwd of the bot.
+    pub fn command(
+        &self,
+        cmd_path: &str,
+        cmd_args: &[String],
+    ) -> Result<StdCommand, CommandSetupError> {
+        let mut cmd_path = PathBuf::from(cmd_path);
+        let exec_dir = self.cmd_exec_dir.as_deref().map(Path::new);
+
+        // set path if given var is available
+        if let Some(exec_dir) = exec_dir {
+            match exec_dir.metadata() {
+                Ok(metadata) if metadata.is_dir() => {}
+                Ok(_) => {
+                    return Err(CommandSetupError::ExecDir(format!(
+                        "`{}` isn't a directory",
+                        exec_dir.display()
+                    )))
+                }
+                Err(err) => {
+                    return Err(CommandSetupError::ExecDir(format!(
+                        "`{}`: {err}",
+                        exec_dir.display()
+                    )))
+                }
+            }
+
+            // relative paths like `./run.sh` are meant relative to the exec dir,
+            // plain names are still looked up in PATH
+            if cmd_path.is_relative() && cmd_path.components().count() > 1 {
+                cmd_path = exec_dir.join(cmd_path);
+            }
+        }
+
+        let mut cmd = StdCommand::new(&cmd_path);
+        if let Some(exec_dir) = exec_dir {
+            cmd.current_dir(exec_dir);
+        }
+        env::apply(
+            &mut cmd,
+            &self.inherit_env,
+            &self.env_files,
+            &self.env,
+            exec_dir,
+        )
+        .map_err(CommandSetupError::Env)?;
+        cmd.args(cmd_args);
+
+        Ok(cmd)
+    }
+}
+
 impl InstanceRunner {
+    /// Interval in which a child that survived SIGKILL is reported.
+    const KILL_WAIT: Duration = Duration::from_secs(10);
+    /// Time a child gets to take a stdin command before the write is given up.
+    const STDIN_TIMEOUT: Duration = Duration::from_secs(5);
+    /// Time to read the rest of the output once the child exited.
+    const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+    #[allow(clippy::new_ret_no_self)]
     pub fn new(
         name: String,
         instance: Instance,
+        logs: SharedLogBuffer,
         sender_out: Sender<HandlerEvents>,
     ) -> Sender<InstanceInEvents> {
         log::trace!("[{name}] Creating new InstanceRunner");
         let (sender, receiver_in) = mpsc::channel::<InstanceInEvents>(5);
-        let runner = InstanceRunner { name, instance };
+        let runner = InstanceRunner {
+            name,
+            instance,
+            logs,
+        };
 
         let name = runner.name.clone();
         tokio::spawn(async move {
             log::trace!("[{}] Spawned runner thread for child", runner.name);
-            let (child, stdout) = runner.spawn_child(&sender_out).await;
-            runner
-                .run_loop(child, stdout, sender_out, receiver_in)
-                .await;
+            runner.supervise(sender_out, receiver_in).await;
             log::trace!("[{}] Finished runner thread for child", runner.name)
         });
 
@@ -84,232 +334,828 @@ impl InstanceRunner {
         sender
     }
 
-    async fn spawn_child(&self, send_out: &Sender<HandlerEvents>) -> (Child, Arc<Mutex<Vec<u8>>>) {
-        log::trace!("[{}] Started spawn_child", self.name);
-        // set path if given var is available
-        if let Some(path) = &self.instance.cmd_exec_dir {
-            if let Err(err) = std::env::set_current_dir(Path::new(&path)) {
-                if let Err(send_err) = send_out
-                    .send(HandlerEvents::InstanceOutEvent(
-                        InstanceOutEvents::ChangeDirFailure,
-                    ))
-                    .await
-                {
-                    panic!("[{}] Failed to change current directory and sending error message. Err: {err}, SendErr: {send_err}", self.name)
-                } else {
-                    panic!(
-                        "[{}] Failed to change current directory. Err: {err}",
-                        self.name
+    /// Runs the child until it exits for good, respawning it on restarts and
+    /// according to the restart policy.
+    async fn supervise(
+        &self,
+        send_out: Sender<HandlerEvents>,
+        receiver_in: Receiver<InstanceInEvents>,
+    ) {
+        let mut receiver = receiver_in;
+        let mut last_exit: Option<String> = None;
+        let mut crashes: VecDeque<Instant> = VecDeque::new();
+        let patterns = StartupPatterns::new(&self.instance.startup);
+        let mut watchdog = self.instance.watchdog.as_ref().map(Watchdog::new);
+
+        loop {
+            let mut run = match self.spawn_child(&send_out).await {
+                Some(run) => run,
+                None => return,
+            };
+            self.send_event(
+                &send_out,
+                InstanceOutEvents::Started(self.name.clone(), run.pid, run.log_position),
+            )
+            .await;
+
+            let (status, recovering) = match self
+                .run_loop(
+                    &mut run,
+                    &patterns,
+                    &mut watchdog,
+                    &send_out,
+                    &mut receiver,
+                    &last_exit,
+                )
+                .await
+            {
+                RunEnd::Restart(status) => {
+                    log::debug!("[{}] Restarting after exit with: {status}", self.name);
+                    last_exit = Some(status.to_string());
+                    continue;
+                }
+                RunEnd::Stopped(status) => {
+                    self.send_event(
+                        &send_out,
+                        InstanceOutEvents::Stopped(self.name.clone(), status.to_string()),
+                    )
+                    .await;
+                    return;
+                }
+                RunEnd::StartupFailed(reason) => {
+                    self.send_event(
+                        &send_out,
+                        InstanceOutEvents::StartupFailed(self.name.clone(), reason),
                     )
+                    .await;
+                    return;
                 }
+                RunEnd::WaitFailed(err) => {
+                    self.send_event(
+                        &send_out,
+                        InstanceOutEvents::StoppedWithError(
+                            self.name.clone(),
+                            format!("couldn't be waited for: {err}"),
+                        ),
+                    )
+                    .await;
+                    return;
+                }
+                RunEnd::Recover(status) => (status, true),
+                RunEnd::Exited(status) => (status, false),
+            };
+            last_exit = Some(status.to_string());
+
+            let policy = &self.instance.restart_policy;
+            // a health check or the watchdog asked for the restart, whatever the policy
+            let restart = recovering
+                || match policy.policy {
+                    RestartPolicy::Never => false,
+                    RestartPolicy::OnFailure => !status.success(),
+                    RestartPolicy::Always => true,
+                };
+            let outcome = if restart {
+                // only restarts within the window count towards a crash loop
+                let window = Duration::from_secs(policy.window);
+                crashes.retain(|crashed_at| crashed_at.elapsed() < window);
+                crashes.push_back(Instant::now());
+                let attempt = crashes.len() as u32;
+
+                if attempt > policy.max_retries {
+                    CrashOutcome::RestartsExhausted(policy.max_retries)
+                } else {
+                    let delay = Duration::from_secs(
+                        policy
+                            .backoff
+                            .saturating_mul(2u64.saturating_pow(attempt - 1))
+                            .min(policy.max_backoff),
+                    );
+                    CrashOutcome::Restarting { attempt, delay }
+                }
+            } else {
+                CrashOutcome::Stopped
+            };
+
+            let event = if !recovering && !status.success() {
+                // the report tells what happens next
+                InstanceOutEvents::Crashed(
+                    self.name.clone(),
+                    self.crash_report(&run, &status, outcome),
+                )
+            } else {
+                let name = self.name.clone();
+                match outcome {
+                    CrashOutcome::Stopped if status.success() => {
+                        InstanceOutEvents::Stopped(name, status.to_string())
+                    }
+                    CrashOutcome::Stopped => {
+                        InstanceOutEvents::StoppedWithError(name, status.to_string())
+                    }
+                    CrashOutcome::Restarting { attempt, delay } => {
+                        InstanceOutEvents::Restarting(name, status.to_string(), attempt, delay)
+                    }
+                    CrashOutcome::RestartsExhausted(retries) => {
+                        InstanceOutEvents::RestartsExhausted(name, status.to_string(), retries)
+                    }
+                }
+            };
+            self.send_event(&send_out, event).await;
+
+            let CrashOutcome::Restarting { delay, .. } = outcome else {
+                return;
+            };
+            if !self.wait_for_restart(delay, &mut receiver).await {
+                self.send_event(
+                    &send_out,
+                    InstanceOutEvents::Stopped(self.name.clone(), status.to_string()),
+                )
+                .await;
+                return;
             }
         }
+    }
 
-        log::trace!("Spawn child");
-        // start child process
-        let mut child = Command::new(&self.instance.cmd_path);
+    /// Waits out the backoff before a restart. Returns `false` if the instance
+    /// was stopped in the meantime, a restart request skips the rest of the wait.
+    async fn wait_for_restart(
+        &self,
+        delay: Duration,
+        receiver: &mut Receiver<InstanceInEvents>,
+    ) -> bool {
+        let backoff = sleep(delay);
+        tokio::pin!(backoff);
+
+        loop {
+            tokio::select! {
+                _ = &mut backoff => return true,
+                event = receiver.recv() => match event {
+                    Some(InstanceInEvents::Stop) | Some(InstanceInEvents::Kill) | None => return false,
+                    Some(InstanceInEvents::Restart) => return true,
+                    // there is no child to report on, dropping the reply answers right away
+                    Some(InstanceInEvents::Status(_)) => {}
+                    Some(InstanceInEvents::ExecuteStdinCommand(cmd)) => {
+                        log::warn!("[{}] Dropping `{cmd}`, waiting for a restart", self.name)
+                    }
+                },
+            }
+        }
+    }
 
-        let child = if let Some(args) = self.instance.cmd_args.clone() {
-            child.args(args)
-        } else {
-            &mut child
+    /// Spawns the child, see [`Instance::command`].
+    ///
+    /// Failures are reported as [`InstanceOutEvents`], `None` means there is no child.
+    async fn spawn_child(&self, send_out: &Sender<HandlerEvents>) -> Option<ChildRun> {
+        log::trace!("[{}] Started spawn_child", self.name);
+        let args = self.instance.cmd_args.as_deref().unwrap_or_default();
+        let mut child = match self.instance.command(&self.instance.cmd_path, args) {
+            Ok(child) => child,
+            Err(CommandSetupError::ExecDir(err)) => {
+                self.send_event(
+                    send_out,
+                    InstanceOutEvents::ExecDirFailure(self.name.clone(), err),
+                )
+                .await;
+                return None;
+            }
+            Err(CommandSetupError::Env(err)) => {
+                self.send_event(
+                    send_out,
+                    InstanceOutEvents::SpawnFailure(self.name.clone(), err),
+                )
+                .await;
+                return None;
+            }
         };
+        let cmd_path = PathBuf::from(child.get_program());
+        log::trace!("[{}] Spawn child {}", self.name, cmd_path.display());
 
-        let mut child = child
+        // own process group, so signals reach everything the child spawns
+        child
+            .process_group(0)
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
+            .stderr(Stdio::piped());
+        let spawned = Command::from(child)
             .spawn()
-            .expect("failed to execute child");
-
-        // todo: maybe also get stderr, stream and analyze
-        let out = match child.stdout.take() {
-            Some(stdout) => {
-                log::trace!("[{}] Collecting child_stream_as_vec", self.name);
-                Self::child_stream_to_vec(stdout)
-            }
-            None => {
-                if let Err(err) = send_out
-                    .send(HandlerEvents::InstanceOutEvent(
-                        InstanceOutEvents::StdoutInitializingFailure,
-                    ))
-                    .await
-                {
-                    panic!("[{}] Couldn't retrieve stdout from spawned child and sending error message. Err: {err}", self.name)
-                } else {
-                    panic!(
-                        "[{}] Couldn't retrieve stdout from spawned child.",
-                        self.name
-                    )
-                }
+            .and_then(|child| match child.id() {
+                Some(pid) => Ok((child, pid)),
+                None => Err(io::Error::other("exited before its pid could be read")),
+            });
+        let (mut child, pid) = match spawned {
+            Ok(spawned) => spawned,
+            Err(err) => {
+                self.send_event(
+                    send_out,
+                    InstanceOutEvents::SpawnFailure(
+                        self.name.clone(),
+                        format!("`{}`: {err}", cmd_path.display()),
+                    ),
+                )
+                .await;
+                return None;
             }
         };
 
-        (child, out)
+        // stdin is kept apart, `Child::wait` would close it otherwise
+        let stdin = child.stdin.take();
+        let output = ChildOutput {
+            stdout: child.stdout.take().map(OutputReader::new),
+            stderr: child.stderr.take().map(OutputReader::new),
+            merge_stderr: self.instance.stderr == StderrMode::Merge,
+        };
+        let mut run = ChildRun {
+            child,
+            pid,
+            stdin,
+            output,
+            started_at: Local::now(),
+            log_position: self
+                .logs
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .position(),
+        };
+
+        if run.output.stdout.is_none() {
+            self.kill(&mut run, RunEnd::Exited).await;
+            self.send_event(
+                send_out,
+                InstanceOutEvents::StdoutInitializingFailure(self.name.clone()),
+            )
+            .await;
+            return None;
+        }
+        Some(run)
     }
 
+    /// Drives a run of the child until it exits or is stopped, reacting to whatever
+    /// happens first: the child exiting, an incoming event, an output line or the
+    /// startup timers. Nothing is polled, so an idle child costs no cpu time.
+    ///
+    /// The instance is ready once a line matches the ready pattern, without one
+    /// `time-to-wait` decides.
     async fn run_loop(
         &self,
-        child: Child,
-        stdout: Arc<Mutex<Vec<u8>>>,
-        send_out: Sender<HandlerEvents>,
-        receiver_in: Receiver<InstanceInEvents>,
-    ) {
-        let mut child = child;
-        let mut receiver = receiver_in;
-
-        let mut reached_timeout = false;
-        let mut now: Instant = Instant::now();
-        let mut last_elapsed_sec = now.elapsed().as_secs();
+        run: &mut ChildRun,
+        patterns: &StartupPatterns,
+        watchdog: &mut Option<Watchdog>,
+        send_out: &Sender<HandlerEvents>,
+        receiver: &mut Receiver<InstanceInEvents>,
+        last_exit: &Option<String>,
+    ) -> RunEnd {
+        let time_to_wait = Duration::from_secs(self.instance.startup.time_to_wait);
+        let startup_timer = sleep(time_to_wait);
+        tokio::pin!(startup_timer);
+        let startup_timeout = self.instance.startup.startup_timeout;
+        let startup_deadline = sleep(Duration::from_secs(startup_timeout.unwrap_or_default()));
+        tokio::pin!(startup_deadline);
+        let spawned_at = Instant::now();
+        let mut health = HealthMonitor::start(&self.name, &self.instance);
+        let mut ready = false;
+        let mut receiver_open = true;
+        if let Some(watchdog) = watchdog.as_mut() {
+            watchdog.disarm();
+        }
 
         log::trace!(
             "[{}] All prerequisites were successful. Starting run loop",
             self.name
         );
         loop {
-            sleep(Duration::from_millis(100)).await;
-
-            if let Ok(Some(status)) = child.try_wait() {
-                log::debug!(
-                    "[{}] Child-Process: {} finished with: {}",
-                    self.name,
-                    self.instance,
-                    status
-                );
-
-                let res = if !status.success() {
-                    send_out
-                        .send(HandlerEvents::InstanceOutEvent(
-                            InstanceOutEvents::StoppedWithError(status.to_string()),
-                        ))
-                        .await
-                } else {
-                    send_out
-                        .send(HandlerEvents::InstanceOutEvent(InstanceOutEvents::Stopped(
-                            self.name.clone(),
-                        )))
-                        .await
-                };
-
-                if let Err(send_err) = res {
-                    panic!(
-                        "[{}] Couldn't send stopped message to HandlerEvents. {}",
-                        self.name, send_err
-                    )
-                } else {
-                    // exit loop
-                    return;
+            tokio::select! {
+                status = run.child.wait() => {
+                    let status = match status {
+                        Ok(status) => status,
+                        Err(err) => {
+                            log::error!("[{}] Couldn't wait for child: {err}", self.name);
+                            // waiting would fail again, so this is all that is left
+                            self.signal(run, Signal::SIGKILL);
+                            return RunEnd::WaitFailed(err);
+                        }
+                    };
+                    log::debug!(
+                        "[{}] Child-Process: {} finished with: {}",
+                        self.name,
+                        self.instance,
+                        status
+                    );
+                    self.drain_output(run).await;
+                    return RunEnd::Exited(status);
                 }
-            }
+                event = receiver.recv(), if receiver_open => match event {
+                    Some(InstanceInEvents::ExecuteStdinCommand(cmd)) => {
+                        if let Err(err) = self.write_stdin(run, &cmd).await {
+                            self.send_event(
+                                send_out,
+                                InstanceOutEvents::ExecuteStdinCommandFailure(
+                                    self.name.clone(),
+                                    err.to_string(),
+                                ),
+                            )
+                            .await;
+                        }
+                    }
+                    Some(InstanceInEvents::Stop) => {
+                        return self.shutdown(run, send_out, receiver, RunEnd::Stopped).await
+                    }
+                    Some(InstanceInEvents::Restart) => {
+                        return self.shutdown(run, send_out, receiver, RunEnd::Restart).await
+                    }
+                    Some(InstanceInEvents::Kill) => return self.kill(run, RunEnd::Stopped).await,
+                    Some(InstanceInEvents::Status(reply)) => {
+                        let status = RunnerStatus {
+                            pid: run.pid,
+                            started_at: run.started_at,
+                            last_exit: last_exit.clone(),
+                            health_checks: health
+                                .as_ref()
+                                .map(HealthMonitor::history)
+                                .unwrap_or_default(),
+                        };
+                        if reply.send(status).is_err() {
+                            log::warn!(
+                                "[{}] Status was requested, but nobody waited for it",
+                                self.name
+                            );
+                        }
+                    }
+                    None => {
+                        log::error!("[{}] Receiver was disconnected", self.name);
+                        receiver_open = false;
+                    }
+                },
+                Some(line) = run.output.next_line(), if run.output.is_open() => {
+                    self.handle_line(&line);
+                    if watchdog.as_mut().is_some_and(|watchdog| watchdog.observe(&line.text)) {
+                        log::info!("[{}] Responsive again", self.name);
+                        self.send_event(send_out, InstanceOutEvents::Responsive(self.name.clone()))
+                            .await;
+                    }
+                    if ready {
+                        continue;
+                    }
 
-            match receiver.try_recv() {
-                Ok(event) => match event {
-                    InstanceInEvents::ExecuteStdinCommand(cmd) => {
-                        if let Err(err) = child
-                            .stdin
+                    if let Some(pattern) = patterns.failure(&line.text) {
+                        log::error!("[{}] Startup failed, `{pattern}` matched", self.name);
+                        let reason = format!("`{}` matched failure pattern `{pattern}`", line.text);
+                        return self.kill(run, |_| RunEnd::StartupFailed(reason)).await;
+                    }
+                    if patterns.is_ready(&line.text) {
+                        ready = true;
+                        self.mark_ready(send_out, watchdog, spawned_at.elapsed(), Some(line.text))
+                            .await;
+                    } else if self.instance.startup.wait_for_stdout {
+                        startup_timer
                             .as_mut()
-                            .expect(&format!(
-                                "[{}] Couldn't retrieve stdin from spawned child.",
-                                self.name
-                            ))
-                            .write(format!("{cmd}\n").as_bytes())
-                        {
-                            if let Err(err) = send_out
-                                .send(HandlerEvents::InstanceOutEvent(
-                                    InstanceOutEvents::ExecuteStdinCommandFailure(err.to_string()),
-                                ))
-                                .await
-                            {
-                                log::error!("[{}] Error during sending [InstanceOutEvents::ExecuteStdinCommandFailure]. Err {err}", self.name)
-                            };
-                        };
+                            .reset(tokio::time::Instant::now() + time_to_wait);
+                    }
+                }
+                event = Watchdog::next_event(watchdog) => match event {
+                    WatchdogEvent::Probe(cmd) => {
+                        if let Err(err) = self.write_stdin(run, &cmd).await {
+                            log::warn!("[{}] Couldn't write watchdog probe `{cmd}`: {err}", self.name);
+                        }
+                    }
+                    WatchdogEvent::Hung(reason) => {
+                        let recovery = watchdog
+                            .as_ref()
+                            .map_or(WatchdogRecovery::Alert, Watchdog::recovery);
+                        log::error!("[{}] Hung, {reason}", self.name);
+                        self.send_event(
+                            send_out,
+                            InstanceOutEvents::Hung(self.name.clone(), reason, recovery),
+                        )
+                        .await;
+                        match recovery {
+                            WatchdogRecovery::Alert => {}
+                            WatchdogRecovery::Restart => {
+                                return self.shutdown(run, send_out, receiver, RunEnd::Recover).await
+                            }
+                            WatchdogRecovery::Kill => return self.kill(run, RunEnd::Exited).await,
+                        }
                     }
                 },
-                Err(err) => {
-                    if let mpsc::error::TryRecvError::Disconnected = err {
-                        log::error!("[{}] Receiver was disconnected", self.name)
+                change = HealthMonitor::next_change(&mut health) => match change {
+                    HealthChange::Healthy if !ready => {
+                        if !patterns.has_ready_pattern() {
+                            ready = true;
+                            self.mark_ready(send_out, watchdog, spawned_at.elapsed(), None).await;
+                        }
+                    }
+                    HealthChange::Healthy => {
+                        log::info!("[{}] Healthy again", self.name);
+                        self.send_event(send_out, InstanceOutEvents::Healthy(self.name.clone()))
+                            .await;
                     }
+                    HealthChange::Unhealthy(reason) => {
+                        let restart = health
+                            .as_ref()
+                            .is_some_and(HealthMonitor::restarts_when_unhealthy);
+                        log::warn!("[{}] Unhealthy: {reason}", self.name);
+                        self.send_event(
+                            send_out,
+                            InstanceOutEvents::Unhealthy(self.name.clone(), reason, restart),
+                        )
+                        .await;
+                        if restart {
+                            return self.shutdown(run, send_out, receiver, RunEnd::Recover).await;
+                        }
+                    }
+                },
+                _ = &mut startup_timer, if !ready && !patterns.has_ready_pattern() && health.is_none() => {
+                    ready = true;
+                    self.mark_ready(send_out, watchdog, spawned_at.elapsed(), None).await;
                 }
+                _ = &mut startup_deadline, if !ready && startup_timeout.is_some() => {
+                    log::error!("[{}] Startup timed out", self.name);
+                    let reason = format!("not ready within {}s", startup_timeout.unwrap_or_default());
+                    return self.kill(run, |_| RunEnd::StartupFailed(reason)).await;
+                }
+            }
+        }
+    }
+
+    fn crash_report(
+        &self,
+        run: &ChildRun,
+        status: &ExitStatus,
+        outcome: CrashOutcome,
+    ) -> CrashReport {
+        let (run_log, dropped, _) = self
+            .logs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .read_from(run.log_position);
+        let last_lines = run_log[run_log
+            .len()
+            .saturating_sub(self.instance.crash_report_lines)..]
+            .to_vec();
+
+        CrashReport {
+            exit: describe_exit(status),
+            started_at: run.started_at,
+            exited_at: Local::now(),
+            command: self.instance.to_string(),
+            last_lines,
+            run_log,
+            dropped,
+            outcome,
+        }
+    }
+
+    /// Reports the child as ready and starts watching it.
+    async fn mark_ready(
+        &self,
+        send_out: &Sender<HandlerEvents>,
+        watchdog: &mut Option<Watchdog>,
+        after: Duration,
+        matched_line: Option<String>,
+    ) {
+        if let Some(watchdog) = watchdog.as_mut() {
+            watchdog.arm();
+        }
+        self.send_event(
+            send_out,
+            InstanceOutEvents::Ready {
+                name: self.name.clone(),
+                after,
+                matched_line,
+            },
+        )
+        .await;
+    }
+
+    fn handle_line(&self, line: &OutputLine) {
+        match line.stream {
+            Stream::Stdout => log::debug!("[{}] {}", self.name, line.text),
+            Stream::Stderr if self.instance.stderr == StderrMode::Warn => {
+                log::warn!("[{}] [stderr] {}", self.name, line.text)
             }
+            Stream::Stderr => log::debug!("[{}] [stderr] {}", self.name, line.text),
+        }
+        self.logs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(line);
+    }
 
-            let mut stream = stdout.lock().await;
+    /// Reads what is left in the pipe after the child exited. Processes the child
+    /// spawned in the background might keep the pipe open, so this is time boxed.
+    async fn drain_output(&self, run: &mut ChildRun) {
+        let drain = async {
+            while let Some(line) = run.output.next_line().await {
+                self.handle_line(&line);
+            }
+        };
+        if timeout(Self::DRAIN_TIMEOUT, drain).await.is_err() {
+            log::debug!(
+                "[{}] Output is still open after the child exited",
+                self.name
+            );
+        }
+        run.output.close();
+    }
 
-            if let Ok(converted_stream) = str::from_utf8(&stream.to_owned()) {
-                if let Some(newline_position) = converted_stream.find("\n") {
-                    // remove line with newline from stream
-                    stream.drain(..(newline_position + 1));
+    async fn write_stdin(&self, run: &mut ChildRun, cmd: &str) -> io::Result<()> {
+        let stdin = run.stdin.as_mut().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                format!(
+                    "[{}] Couldn't retrieve stdin from spawned child.",
+                    self.name
+                ),
+            )
+        })?;
 
-                    let split = converted_stream.split("\n").collect::<Vec<&str>>();
-                    log::debug!("[{}] {}", self.name, split.get(0).unwrap());
+        // a child that doesn't read its stdin would block the runner otherwise
+        let write = async {
+            stdin.write_all(format!("{cmd}\n").as_bytes()).await?;
+            stdin.flush().await
+        };
+        timeout(Self::STDIN_TIMEOUT, write)
+            .await
+            .unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("[{}] Child didn't read its stdin.", self.name),
+                ))
+            })
+    }
 
-                    if self.instance.startup.wait_for_stdout {
-                        now = Instant::now();
+    /// Runs the configured shutdown sequence, reporting every escalation.
+    ///
+    /// Without a `stdin-cmd` the `stdin` of the `stop` slash command is written, without
+    /// either the sequence starts with the signal.
+    ///
+    /// `end` tells why the run ended once the child exited. A `kill` received meanwhile
+    /// skips the rest of the sequence and ends the run as stopped, even for a restart.
+    async fn shutdown(
+        &self,
+        run: &mut ChildRun,
+        send_out: &Sender<HandlerEvents>,
+        receiver: &mut Receiver<InstanceInEvents>,
+        end: fn(ExitStatus) -> RunEnd,
+    ) -> RunEnd {
+        let shutdown = &self.instance.shutdown;
+        let stop_cmd = shutdown.stdin_cmd.as_deref().or_else(|| {
+            self.instance
+                .slash_commands
+                .get("stop")
+                .and_then(|slash_cmd| slash_cmd.stdin.as_ref())
+                .map(|stdin| stdin.cmd.as_str())
+        });
+
+        if let Some(cmd) = stop_cmd {
+            log::debug!("[{}] Sending stop command `{cmd}`", self.name);
+            match self.write_stdin(run, cmd).await {
+                Ok(()) => {
+                    let time_to_wait = Duration::from_secs(shutdown.time_to_wait);
+                    match self.wait_for_exit(run, time_to_wait, Some(receiver)).await {
+                        ExitWait::Exited(status) => return end(status),
+                        ExitWait::KillRequested => return self.kill_on_request(run).await,
+                        ExitWait::Failed(err) => return RunEnd::WaitFailed(err),
+                        ExitWait::TimedOut => {}
                     }
+                    self.report_shutdown_step(
+                        send_out,
+                        format!(
+                            "`{}` didn't exit within {}s after `{cmd}`.",
+                            self.name, shutdown.time_to_wait
+                        ),
+                    )
+                    .await;
+                }
+                Err(err) => {
+                    self.report_shutdown_step(
+                        send_out,
+                        format!("Couldn't write `{cmd}` to `{}`: {err}", self.name),
+                    )
+                    .await
                 }
             }
+        }
 
-            if !reached_timeout {
-                let current_elapsed = now.elapsed().as_secs();
-
-                if current_elapsed > last_elapsed_sec {
-                    last_elapsed_sec = current_elapsed;
-                    log::trace!("{current_elapsed}s");
-                } else if current_elapsed > self.instance.startup.time_to_wait {
-                    if let Err(err) = send_out
-                        .send(HandlerEvents::InstanceOutEvent(
-                            InstanceOutEvents::StartupTimeoutFinished(self.name.to_string()),
-                        ))
-                        .await
-                    {
-                        log::error!("[{}] Error during sending [InstanceOutEvents::StartupTimeoutFinished]. Err: {err}", self.name)
-                    };
-                    // reset timer
-                    now = Instant::now();
-                    reached_timeout = true;
+        let signal = shutdown
+            .signal
+            .parse::<Signal>()
+            .or_else(|_| format!("SIG{}", shutdown.signal.to_uppercase()).parse())
+            .unwrap_or_else(|_| {
+                log::error!(
+                    "[{}] Unknown shutdown signal `{}`, using SIGTERM",
+                    self.name,
+                    shutdown.signal
+                );
+                Signal::SIGTERM
+            });
+
+        self.report_shutdown_step(send_out, format!("Sending {signal} to `{}`.", self.name))
+            .await;
+        self.signal(run, signal);
+        let kill_timeout = Duration::from_secs(shutdown.kill_timeout);
+        match self.wait_for_exit(run, kill_timeout, Some(receiver)).await {
+            ExitWait::Exited(status) => return end(status),
+            ExitWait::KillRequested => return self.kill_on_request(run).await,
+            ExitWait::Failed(err) => return RunEnd::WaitFailed(err),
+            ExitWait::TimedOut => {}
+        }
+
+        self.report_shutdown_step(
+            send_out,
+            format!(
+                "`{}` didn't exit within {}s after {signal}, killing it.",
+                self.name, shutdown.kill_timeout
+            ),
+        )
+        .await;
+        self.kill(run, end).await
+    }
+
+    async fn report_shutdown_step(&self, send_out: &Sender<HandlerEvents>, step: String) {
+        log::info!("[{}] {step}", self.name);
+        self.send_event(
+            send_out,
+            InstanceOutEvents::ShutdownStep(self.name.clone(), step),
+        )
+        .await;
+    }
+
+    async fn kill_on_request(&self, run: &mut ChildRun) -> RunEnd {
+        log::info!("[{}] Killing during the shutdown sequence", self.name);
+        self.kill(run, RunEnd::Stopped).await
+    }
+
+    /// Kills the whole process group of the child, `end` tells why the run ended once
+    /// it exited.
+    async fn kill(&self, run: &mut ChildRun, end: impl FnOnce(ExitStatus) -> RunEnd) -> RunEnd {
+        self.signal(run, Signal::SIGKILL);
+
+        loop {
+            match self.wait_for_exit(run, Self::KILL_WAIT, None).await {
+                ExitWait::Exited(status) => return end(status),
+                ExitWait::Failed(err) => return RunEnd::WaitFailed(err),
+                ExitWait::TimedOut | ExitWait::KillRequested => {
+                    log::error!("[{}] Still alive after SIGKILL, waiting", self.name)
                 }
             }
         }
     }
 
-    /// https://stackoverflow.com/a/34616729/10386701
-    /// Pipe streams are blocking, we need separate threads to monitor them without blocking the primary thread.
-    fn child_stream_to_vec<R>(stream: R) -> Arc<Mutex<Vec<u8>>>
-    where
-        R: Read + Send + 'static,
-    {
-        log::trace!("Starting stream reading thread");
-        let out = Arc::new(Mutex::new(Vec::new()));
-        let vec = out.clone();
+    fn signal(&self, run: &ChildRun, signal: Signal) {
+        log::debug!(
+            "[{}] Sending {signal} to process group {}",
+            self.name,
+            run.pid
+        );
+        if let Err(err) = killpg(Pid::from_raw(run.pid as i32), signal) {
+            log::error!("[{}] Couldn't send {signal}: {err}", self.name);
+        }
+    }
 
-        tokio::spawn(Self::read_stream_loop(stream, vec));
-        log::trace!("Finished starting stream reading thread");
+    /// Waits for the child to exit, while still reading its output. A child
+    /// blocked on a full pipe would never get to exit otherwise.
+    ///
+    /// With a `receiver` a requested `kill` ends the wait, other events are dropped.
+    async fn wait_for_exit(
+        &self,
+        run: &mut ChildRun,
+        time: Duration,
+        receiver: Option<&mut Receiver<InstanceInEvents>>,
+    ) -> ExitWait {
+        let mut receiver = receiver;
+        let deadline = sleep(time);
+        tokio::pin!(deadline);
 
-        out
+        loop {
+            tokio::select! {
+                status = run.child.wait() => match status {
+                    Ok(status) => {
+                        self.drain_output(run).await;
+                        return ExitWait::Exited(status);
+                    }
+                    Err(err) => {
+                        log::error!("[{}] Couldn't wait for child: {err}", self.name);
+                        return ExitWait::Failed(err);
+                    }
+                },
+                Some(line) = run.output.next_line(), if run.output.is_open() => {
+                    self.handle_line(&line);
+                }
+                event = Self::next_event(&mut receiver) => match event {
+                    Some(InstanceInEvents::Kill) => return ExitWait::KillRequested,
+                    // the child is on its way out, dropping the reply answers right away
+                    Some(InstanceInEvents::Status(_)) => {}
+                    Some(event) => log::warn!("[{}] Dropping {event:?}, stopping", self.name),
+                    None => receiver = None,
+                },
+                _ = &mut deadline => return ExitWait::TimedOut,
+            }
+        }
     }
 
-    async fn read_stream_loop<R>(mut stream: R, vec: Arc<Mutex<Vec<u8>>>)
-    where
-        R: Read + Send + 'static,
-    {
-        log::trace!("Started stream reading thread");
+    /// The next incoming event, pending forever without a receiver. Cancel safe.
+    async fn next_event(
+        receiver: &mut Option<&mut Receiver<InstanceInEvents>>,
+    ) -> Option<InstanceInEvents> {
+        match receiver {
+            Some(receiver) => receiver.recv().await,
+            None => future::pending().await,
+        }
+    }
+
+    async fn send_event(&self, send_out: &Sender<HandlerEvents>, event: InstanceOutEvents) {
+        if let Err(err) = send_out.send(HandlerEvents::InstanceOutEvent(event)).await {
+            log::error!(
+                "[{}] Error during sending InstanceOutEvent. Err: {err}",
+                self.name
+            )
+        }
+    }
+}
+
+impl ChildOutput {
+    fn is_open(&self) -> bool {
+        self.stdout.is_some() || self.stderr.is_some()
+    }
+
+    fn close(&mut self) {
+        self.stdout = None;
+        self.stderr = None;
+    }
+
+    /// The next line of either pipe, `None` once both are closed. Cancel safe.
+    async fn next_line(&mut self) -> Option<OutputLine> {
         loop {
-            let mut buf = [0];
-            match stream.read(&mut buf) {
-                Err(err) => {
-                    log::error!("{}] Error reading from stream: {}", line!(), err);
-                    break;
+            let (stream, line) = tokio::select! {
+                line = Self::read(&mut self.stdout), if self.stdout.is_some() => (Stream::Stdout, line),
+                line = Self::read(&mut self.stderr), if self.stderr.is_some() => (Stream::Stderr, line),
+                else => return None,
+            };
+
+            match (line, stream) {
+                (Some(text), Stream::Stderr) if !self.merge_stderr => {
+                    return Some(OutputLine { stream, text })
                 }
-                Ok(got) => {
-                    if got == 0 {
-                        break;
-                    } else if got == 1 {
-                        vec.lock().await.push(buf[0])
-                    } else {
-                        log::error!("{}] Unexpected number of bytes: {}", line!(), got);
-                        break;
-                    }
+                (Some(text), _) => {
+                    return Some(OutputLine {
+                        stream: Stream::Stdout,
+                        text,
+                    })
                 }
+                (None, Stream::Stdout) => self.stdout = None,
+                (None, Stream::Stderr) => self.stderr = None,
+            }
+        }
+    }
+
+    /// The next line of a pipe, `None` once it is closed or broken.
+    async fn read<R: AsyncRead + Unpin>(output: &mut Option<OutputReader<R>>) -> Option<String> {
+        match output.as_mut()?.next_line().await {
+            Ok(line) => line,
+            Err(err) => {
+                log::error!("Error reading from stream: {err}");
+                None
             }
         }
     }
 }
+
+impl<R: AsyncRead + Unpin> OutputReader<R> {
+    /// Longer lines are split, output without line breaks would grow the buffer forever.
+    const MAX_LINE_LENGTH: usize = 16 * 1024;
+
+    fn new(stream: R) -> Self {
+        OutputReader {
+            reader: BufReader::new(stream),
+            buf: Vec::new(),
+        }
+    }
+
+    /// Cancel safe, a partially read line stays in the buffer until the rest arrives.
+    async fn next_line(&mut self) -> io::Result<Option<String>> {
+        while self.buf.len() < Self::MAX_LINE_LENGTH {
+            let available = self.reader.fill_buf().await?;
+            if available.is_empty() {
+                break;
+            }
+
+            let room = Self::MAX_LINE_LENGTH - self.buf.len();
+            let (read, line_end) = match available.iter().take(room).position(|&b| b == b'\n') {
+                Some(index) => (index + 1, true),
+                None => (available.len().min(room), false),
+            };
+            self.buf.extend_from_slice(&available[..read]);
+            self.reader.consume(read);
+            if line_end {
+                break;
+            }
+        }
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+
+        let line = String::from_utf8_lossy(&self.buf);
+        let line = line.trim_end_matches(['\n', '\r']).to_string();
+        self.buf.clear();
+        Ok(Some(line))
+    }
+}
@@ -0,0 +1,52 @@
+use std::{os::unix::process::ExitStatusExt, process::ExitStatus, time::Duration};
+
+use chrono::{DateTime, Local};
+use nix::sys::signal::Signal;
+
+use super::logs::LogLine;
+
+/// What is known about a run that ended with a non-zero or signal status.
+#[derive(Debug)]
+pub struct CrashReport {
+    pub exit: String,
+    pub started_at: DateTime<Local>,
+    pub exited_at: DateTime<Local>,
+    // the `Display` of the instance
+    pub command: String,
+    pub last_lines: Vec<LogLine>,
+    // every line of the run still in the log buffer
+    pub run_log: Vec<LogLine>,
+    // lines of the run that were already dropped from the log buffer
+    pub dropped: u64,
+    pub outcome: CrashOutcome,
+}
+
+/// What the runner does after a crash.
+#[derive(Clone, Copy, Debug)]
+pub enum CrashOutcome {
+    Stopped,
+    Restarting { attempt: u32, delay: Duration },
+    // the restart would exceed this many restarts within the window
+    RestartsExhausted(u32),
+}
+
+/// `exit code 1` or `signal SIGSEGV (core dumped)`.
+pub fn describe_exit(status: &ExitStatus) -> String {
+    if let Some(code) = status.code() {
+        return format!("exit code {code}");
+    }
+
+    match status.signal() {
+        Some(signal) => {
+            let name = Signal::try_from(signal)
+                .map(|signal| signal.to_string())
+                .unwrap_or_else(|_| signal.to_string());
+            if status.core_dumped() {
+                format!("signal {name} (core dumped)")
+            } else {
+                format!("signal {name}")
+            }
+        }
+        None => status.to_string(),
+    }
+}
@@ -0,0 +1,128 @@
+use std::{collections::HashMap, env, fmt::Debug, path::Path, process::Command};
+
+use serde::{Deserialize, Serialize};
+
+/// Environment variables set for the child. Values may reference the environment of
+/// the bot with `${VAR}` and are masked in debug output, as they often hold secrets.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct EnvVars(pub HashMap<String, String>);
+
+/// Which variables of the bot's environment the child inherits.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum InheritEnv {
+    All(bool),
+    Allowlist(Vec<String>),
+}
+
+impl Default for InheritEnv {
+    fn default() -> Self {
+        InheritEnv::All(true)
+    }
+}
+
+impl Debug for EnvVars {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_map()
+            .entries(self.0.keys().map(|key| (key, "***")))
+            .finish()
+    }
+}
+
+/// Sets up the environment of a child: the inherited variables first, then the env
+/// files in order and the `env` table last, so later sources override earlier ones.
+///
+/// Relative env files are resolved against the exec dir, if there is one.
+pub fn apply(
+    cmd: &mut Command,
+    inherit_env: &InheritEnv,
+    env_files: &[String],
+    env_vars: &EnvVars,
+    exec_dir: Option<&Path>,
+) -> Result<(), String> {
+    match inherit_env {
+        InheritEnv::All(true) => {}
+        InheritEnv::All(false) => {
+            cmd.env_clear();
+        }
+        InheritEnv::Allowlist(keys) => {
+            cmd.env_clear();
+            for key in keys {
+                if let Some(value) = env::var_os(key) {
+                    cmd.env(key, value);
+                }
+            }
+        }
+    }
+
+    for env_file in env_files {
+        let path = match exec_dir {
+            Some(exec_dir) => exec_dir.join(env_file),
+            None => Path::new(env_file).to_path_buf(),
+        };
+        let vars = dotenvy::from_path_iter(&path)
+            .map_err(|err| format!("Couldn't read env file `{}`: {err}", path.display()))?;
+
+        for (index, var) in vars.enumerate() {
+            let (key, value) = var.map_err(|err| {
+                format!(
+                    "Couldn't parse env file `{}`: {}",
+                    path.display(),
+                    describe_error(&err, index)
+                )
+            })?;
+            cmd.env(key, value);
+        }
+    }
+
+    for (key, value) in &env_vars.0 {
+        cmd.env(
+            key,
+            interpolate(value).map_err(|err| format!("`{key}`: {err}"))?,
+        );
+    }
+
+    Ok(())
+}
+
+/// Describes why an env file couldn't be parsed without the offending line, which
+/// dotenvy quotes in full, value included. Only a key that looks like one is named.
+fn describe_error(err: &dotenvy::Error, index: usize) -> String {
+    let dotenvy::Error::LineParse(line, _) = err else {
+        return err.to_string();
+    };
+    let key = line
+        .split_once('=')
+        .map(|(key, _)| key.trim().trim_start_matches("export ").trim())
+        .filter(|key| {
+            !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        });
+
+    match key {
+        Some(key) => format!("invalid entry {} (`{key}`)", index + 1),
+        None => format!("invalid entry {}", index + 1),
+    }
+}
+
+/// Replaces `${VAR}` with the value of `VAR` in the bot's environment.
+fn interpolate(value: &str) -> Result<String, String> {
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after.find('}').ok_or_else(|| String::from("Missing `}`"))?;
+        let name = &after[..end];
+
+        match env::var(name) {
+            Ok(var) => result.push_str(&var),
+            Err(err) => return Err(format!("Couldn't resolve `${{{name}}}`: {err}")),
+        }
+        rest = &after[end + 1..];
+    }
+    result.push_str(rest);
+
+    Ok(result)
+}
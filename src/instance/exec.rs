@@ -0,0 +1,104 @@
+use std::{
+    io,
+    os::unix::process::CommandExt,
+    process::{ExitStatus, Stdio},
+};
+
+use nix::{
+    sys::signal::{killpg, Signal},
+    unistd::Pid,
+};
+use tokio::process::{Child, Command};
+
+use super::{ChildOutput, Instance, OutputLine, OutputReader};
+
+/// A one-shot program run in the exec dir and environment of an instance, next to
+/// its long-running child. The whole process group is killed if the run is dropped
+/// before it exited.
+pub struct ExecRun {
+    child: Child,
+    pid: u32,
+    output: ChildOutput,
+    exited: bool,
+}
+
+/// What happened next during an [`ExecRun`].
+pub enum ExecEvent {
+    Line(OutputLine),
+    Exited(io::Result<ExitStatus>),
+}
+
+impl ExecRun {
+    pub fn spawn(
+        instance: &Instance,
+        cmd_path: &str,
+        cmd_args: &[String],
+    ) -> Result<ExecRun, String> {
+        let mut cmd = instance
+            .command(cmd_path, cmd_args)
+            .map_err(|err| err.to_string())?;
+        // own process group, so a timeout reaches everything the program spawns
+        cmd.process_group(0)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        let mut child = Command::from(cmd)
+            .spawn()
+            .map_err(|err| format!("`{cmd_path}`: {err}"))?;
+        let pid = child
+            .id()
+            .ok_or_else(|| format!("`{cmd_path}` exited before its pid could be read"))?;
+        let output = ChildOutput {
+            stdout: child.stdout.take().map(OutputReader::new),
+            stderr: child.stderr.take().map(OutputReader::new),
+            merge_stderr: true,
+        };
+
+        Ok(ExecRun {
+            child,
+            pid,
+            output,
+            exited: false,
+        })
+    }
+
+    /// The next line of stdout or stderr, `None` once both are closed. Cancel safe.
+    pub async fn next_line(&mut self) -> Option<OutputLine> {
+        self.output.next_line().await
+    }
+
+    /// The next line of output or the exit of the program, lines still in the pipes
+    /// after the exit are left to [`ExecRun::next_line`]. Cancel safe.
+    pub async fn next_event(&mut self) -> ExecEvent {
+        tokio::select! {
+            Some(line) = self.output.next_line(), if self.output.is_open() => ExecEvent::Line(line),
+            status = self.child.wait() => {
+                self.exited = status.is_ok();
+                ExecEvent::Exited(status)
+            }
+        }
+    }
+
+    /// Kills the whole process group.
+    pub async fn kill(&mut self) -> io::Result<ExitStatus> {
+        self.signal();
+        let status = self.child.wait().await?;
+        self.exited = true;
+        Ok(status)
+    }
+
+    fn signal(&self) {
+        if let Err(err) = killpg(Pid::from_raw(self.pid as i32), Signal::SIGKILL) {
+            log::error!("Couldn't kill process group {}: {err}", self.pid);
+        }
+    }
+}
+
+impl Drop for ExecRun {
+    fn drop(&mut self) {
+        if !self.exited {
+            self.signal();
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    future,
+    process::Stdio,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Local};
+use tokio::{
+    net::TcpStream,
+    process::Command,
+    sync::mpsc::{self, Receiver, Sender},
+    time::{interval, timeout, MissedTickBehavior},
+};
+
+use super::Instance;
+use crate::config::bot::{HealthCheckConfig, HealthProbe};
+
+/// Outcome of a single probe.
+#[derive(Clone, Debug)]
+pub struct HealthCheckResult {
+    pub at: DateTime<Local>,
+    // how long a passed probe took, or why it failed
+    pub outcome: Result<Duration, String>,
+}
+
+/// A flip between healthy and unhealthy, as decided by the thresholds.
+#[derive(Debug)]
+pub enum HealthChange {
+    Healthy,
+    Unhealthy(String),
+}
+
+/// Runs the probes of a child in the background and keeps track of their results.
+/// Probing stops once the monitor is dropped.
+pub struct HealthMonitor {
+    cfg: HealthCheckConfig,
+    results: Receiver<HealthCheckResult>,
+    history: VecDeque<HealthCheckResult>,
+    failures: u32,
+    successes: u32,
+    healthy: bool,
+}
+
+impl Display for HealthCheckResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let at = self.at.format("%H:%M:%S");
+        match &self.outcome {
+            Ok(took) => write!(f, "{at} passed ({}ms)", took.as_millis()),
+            Err(err) => write!(f, "{at} failed: {err}"),
+        }
+    }
+}
+
+impl HealthMonitor {
+    /// Results kept for `/status`.
+    const MAX_HISTORY: usize = 10;
+
+    /// `None` if the instance has no health check configured.
+    pub fn start(name: &str, instance: &Instance) -> Option<HealthMonitor> {
+        let cfg = instance.health_check.clone()?;
+        let (sender, results) = mpsc::channel(1);
+
+        tokio::spawn(run_probes(
+            name.to_string(),
+            instance.clone(),
+            cfg.clone(),
+            sender,
+        ));
+
+        Some(HealthMonitor {
+            cfg,
+            results,
+            history: VecDeque::new(),
+            failures: 0,
+            successes: 0,
+            healthy: false,
+        })
+    }
+
+    pub fn restarts_when_unhealthy(&self) -> bool {
+        self.cfg.restart
+    }
+
+    /// Oldest result first.
+    pub fn history(&self) -> Vec<HealthCheckResult> {
+        self.history.iter().cloned().collect()
+    }
+
+    /// Waits for the next change, pending forever without a monitor. Cancel safe.
+    pub async fn next_change(monitor: &mut Option<HealthMonitor>) -> HealthChange {
+        match monitor {
+            Some(monitor) => monitor.next().await,
+            None => future::pending().await,
+        }
+    }
+
+    async fn next(&mut self) -> HealthChange {
+        loop {
+            let result = match self.results.recv().await {
+                Some(result) => result,
+                None => future::pending().await,
+            };
+            if let Some(change) = self.record(result) {
+                return change;
+            }
+        }
+    }
+
+    fn record(&mut self, result: HealthCheckResult) -> Option<HealthChange> {
+        let change = match &result.outcome {
+            Ok(_) => {
+                self.failures = 0;
+                self.successes += 1;
+                (!self.healthy && self.successes >= self.cfg.success_threshold)
+                    .then_some(HealthChange::Healthy)
+            }
+            Err(err) => {
+                self.successes = 0;
+                self.failures += 1;
+                // a child that never passed is still starting, not unhealthy
+                (self.healthy && self.failures >= self.cfg.failure_threshold).then(|| {
+                    HealthChange::Unhealthy(format!(
+                        "{} checks in a row failed, last one: {err}",
+                        self.failures
+                    ))
+                })
+            }
+        };
+
+        match change {
+            Some(HealthChange::Healthy) => self.healthy = true,
+            Some(HealthChange::Unhealthy(_)) => self.healthy = false,
+            None => {}
+        }
+
+        if self.history.len() >= Self::MAX_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(result);
+
+        change
+    }
+}
+
+async fn run_probes(
+    name: String,
+    instance: Instance,
+    cfg: HealthCheckConfig,
+    sender: Sender<HealthCheckResult>,
+) {
+    let probe_timeout = Duration::from_secs(cfg.timeout);
+    let http = match reqwest::Client::builder().timeout(probe_timeout).build() {
+        Ok(http) => http,
+        Err(err) => {
+            log::error!("[{name}] Couldn't create http client for health checks: {err}");
+            return;
+        }
+    };
+
+    let mut ticks = interval(Duration::from_secs(cfg.interval.max(1)));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {}
+            _ = sender.closed() => break,
+        }
+
+        let at = Local::now();
+        let started = Instant::now();
+        let outcome = match timeout(probe_timeout, probe(&cfg.probe, &instance, &http)).await {
+            Ok(Ok(())) => Ok(started.elapsed()),
+            Ok(Err(err)) => Err(err),
+            Err(_) => Err(format!("timed out after {}s", cfg.timeout)),
+        };
+        log::trace!("[{name}] Health check: {outcome:?}");
+
+        if sender
+            .send(HealthCheckResult { at, outcome })
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+    log::trace!("[{name}] Stopped health checks");
+}
+
+async fn probe(
+    probe: &HealthProbe,
+    instance: &Instance,
+    http: &reqwest::Client,
+) -> Result<(), String> {
+    match probe {
+        HealthProbe::Tcp { address } => TcpStream::connect(address)
+            .await
+            .map(|_| ())
+            .map_err(|err| format!("couldn't connect to `{address}`: {err}")),
+        HealthProbe::Http {
+            url,
+            expected_status,
+        } => {
+            let response = http.get(url).send().await.map_err(|err| err.to_string())?;
+            if response.status().as_u16() == *expected_status {
+                Ok(())
+            } else {
+                Err(format!("`{url}` returned {}", response.status()))
+            }
+        }
+        HealthProbe::Exec {
+            cmd_path,
+            cmd_args,
+            expected_code,
+        } => {
+            let cmd = instance
+                .command(cmd_path, cmd_args)
+                .map_err(|err| err.to_string())?;
+            let status = Command::from(cmd)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                // the probe is dropped on a timeout
+                .kill_on_drop(true)
+                .status()
+                .await
+                .map_err(|err| format!("`{cmd_path}`: {err}"))?;
+
+            match status.code() {
+                Some(code) if code == *expected_code => Ok(()),
+                _ => Err(format!("`{cmd_path}` exited with {status}")),
+            }
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Local};
+use regex::Regex;
+use tokio::sync::watch;
+
+use super::{OutputLine, Stream};
+
+/// The log buffer of an instance, shared by its runner and the handler.
+pub type SharedLogBuffer = Arc<Mutex<LogBuffer>>;
+
+/// A line of output as it is kept in the [`LogBuffer`].
+#[derive(Clone, Debug)]
+pub struct LogLine {
+    pub at: DateTime<Local>,
+    pub stream: Stream,
+    pub text: String,
+}
+
+/// The most recent output lines of an instance, the oldest ones are dropped first.
+#[derive(Debug)]
+pub struct LogBuffer {
+    lines: VecDeque<LogLine>,
+    capacity: usize,
+    // number of lines ever pushed, the position of the next line
+    next_position: u64,
+    // sends `next_position` on every push
+    pushed: watch::Sender<u64>,
+}
+
+impl LogLine {
+    /// `14:02:03 [stderr] text`, stdout lines aren't tagged. With the date for files.
+    pub fn format(&self, with_date: bool) -> String {
+        let at = if with_date {
+            self.at.format("%Y-%m-%d %H:%M:%S")
+        } else {
+            self.at.format("%H:%M:%S")
+        };
+        match self.stream {
+            Stream::Stdout => format!("{at} {}", self.text),
+            Stream::Stderr => format!("{at} [stderr] {}", self.text),
+        }
+    }
+}
+
+impl LogBuffer {
+    pub fn shared(capacity: usize) -> SharedLogBuffer {
+        Arc::new(Mutex::new(LogBuffer {
+            lines: VecDeque::with_capacity(capacity.min(1024)),
+            capacity,
+            next_position: 0,
+            pushed: watch::channel(0).0,
+        }))
+    }
+
+    /// Wakes up on every pushed line, to wait for output without polling.
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.pushed.subscribe()
+    }
+
+    pub fn push(&mut self, line: &OutputLine) {
+        self.next_position += 1;
+        if self.capacity > 0 {
+            if self.lines.len() >= self.capacity {
+                self.lines.pop_front();
+            }
+            self.lines.push_back(LogLine {
+                at: Local::now(),
+                stream: line.stream,
+                text: line.text.clone(),
+            });
+        }
+        self.pushed.send_replace(self.next_position);
+    }
+
+    /// Where the next line will be, to read everything pushed from now on.
+    pub fn position(&self) -> u64 {
+        self.next_position
+    }
+
+    /// The lines pushed since `position` and how many of them were already dropped,
+    /// followed by the position to continue from.
+    pub fn read_from(&self, position: u64) -> (Vec<LogLine>, u64, u64) {
+        let first_kept = self.next_position - self.lines.len() as u64;
+        let start = position.max(first_kept);
+        let lines = self
+            .lines
+            .iter()
+            .skip((start - first_kept) as usize)
+            .cloned()
+            .collect();
+
+        (lines, start - position, self.next_position)
+    }
+
+    /// The last `count` lines matching `grep` that were written after `since`, oldest first.
+    pub fn query(
+        &self,
+        count: usize,
+        grep: Option<&Regex>,
+        since: Option<DateTime<Local>>,
+    ) -> Vec<LogLine> {
+        let mut lines: Vec<LogLine> = self
+            .lines
+            .iter()
+            .rev()
+            .take_while(|line| since.is_none_or(|since| line.at >= since))
+            .filter(|line| grep.is_none_or(|grep| grep.is_match(&line.text)))
+            .take(count)
+            .cloned()
+            .collect();
+        lines.reverse();
+        lines
+    }
+}
@@ -0,0 +1,37 @@
+use crate::config::bot::{ConfigRegex, StartupConfig};
+
+/// The `ready-pattern` and `failure-patterns` of a [`StartupConfig`].
+pub struct StartupPatterns {
+    ready: Vec<ConfigRegex>,
+    failure: Vec<ConfigRegex>,
+}
+
+impl StartupPatterns {
+    pub fn new(startup: &StartupConfig) -> StartupPatterns {
+        StartupPatterns {
+            ready: startup
+                .ready_pattern
+                .as_ref()
+                .map(|patterns| patterns.as_slice().to_vec())
+                .unwrap_or_default(),
+            failure: startup.failure_patterns.clone(),
+        }
+    }
+
+    /// Without a ready pattern, the startup timer decides when the instance is ready.
+    pub fn has_ready_pattern(&self) -> bool {
+        !self.ready.is_empty()
+    }
+
+    pub fn is_ready(&self, line: &str) -> bool {
+        self.ready.iter().any(|pattern| pattern.is_match(line))
+    }
+
+    /// The failure pattern the line matched, if any.
+    pub fn failure(&self, line: &str) -> Option<&str> {
+        self.failure
+            .iter()
+            .find(|pattern| pattern.is_match(line))
+            .map(|pattern| pattern.as_str())
+    }
+}
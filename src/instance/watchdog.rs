@@ -0,0 +1,113 @@
+use std::{future, pin::Pin, time::Duration};
+
+use tokio::time::{interval_at, sleep, Instant, Interval, MissedTickBehavior, Sleep};
+
+use crate::config::bot::{WatchdogConfig, WatchdogRecovery};
+
+/// What the watchdog wants the runner to do.
+#[derive(Debug)]
+pub enum WatchdogEvent {
+    /// write the probe command to stdin
+    Probe(String),
+    /// no heartbeat within the timeout, with the reason
+    Hung(String),
+}
+
+/// Keeps track of the heartbeats of a child, armed once the child is ready.
+pub struct Watchdog {
+    cfg: WatchdogConfig,
+    deadline: Pin<Box<Sleep>>,
+    probe: Option<Interval>,
+    armed: bool,
+    hung: bool,
+}
+
+impl Watchdog {
+    pub fn new(cfg: &WatchdogConfig) -> Watchdog {
+        Watchdog {
+            cfg: cfg.clone(),
+            deadline: Box::pin(sleep(Duration::ZERO)),
+            probe: None,
+            armed: false,
+            hung: false,
+        }
+    }
+
+    pub fn recovery(&self) -> WatchdogRecovery {
+        self.cfg.recovery
+    }
+
+    /// Starts watching, called once the child is ready.
+    pub fn arm(&mut self) {
+        let now = Instant::now();
+        self.armed = true;
+        self.hung = false;
+        self.deadline
+            .as_mut()
+            .reset(now + Duration::from_secs(self.cfg.timeout));
+
+        self.probe = self.cfg.probe_cmd.as_ref().map(|_| {
+            let period = Duration::from_secs(self.cfg.probe_interval.max(1));
+            let mut probe = interval_at(now + period, period);
+            probe.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            probe
+        });
+    }
+
+    /// Stops watching until the next [`Watchdog::arm`], e.g. for a new run of the child.
+    pub fn disarm(&mut self) {
+        self.armed = false;
+        self.hung = false;
+        self.probe = None;
+    }
+
+    /// Feeds a line of output. Returns `true` if it ends a hang.
+    pub fn observe(&mut self, line: &str) -> bool {
+        let is_heartbeat = self
+            .cfg
+            .heartbeat_pattern
+            .as_ref()
+            .is_none_or(|pattern| pattern.is_match(line));
+        if !self.armed || !is_heartbeat {
+            return false;
+        }
+
+        self.deadline
+            .as_mut()
+            .reset(Instant::now() + Duration::from_secs(self.cfg.timeout));
+        std::mem::replace(&mut self.hung, false)
+    }
+
+    /// Waits for the next event, pending forever without a watchdog. Cancel safe.
+    pub async fn next_event(watchdog: &mut Option<Watchdog>) -> WatchdogEvent {
+        match watchdog {
+            Some(watchdog) if watchdog.armed => watchdog.next().await,
+            _ => future::pending().await,
+        }
+    }
+
+    async fn next(&mut self) -> WatchdogEvent {
+        let probe_cmd = self.cfg.probe_cmd.clone().unwrap_or_default();
+        let probe = async {
+            match self.probe.as_mut() {
+                Some(probe) => probe.tick().await,
+                None => future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            _ = &mut self.deadline, if !self.hung => {
+                self.hung = true;
+                WatchdogEvent::Hung(match &self.cfg.heartbeat_pattern {
+                    Some(pattern) => format!(
+                        "no line matched `{}` within {}s",
+                        pattern.as_str(),
+                        self.cfg.timeout
+                    ),
+                    None => format!("no output within {}s", self.cfg.timeout),
+                })
+            }
+            _ = probe => WatchdogEvent::Probe(probe_cmd),
+        }
+    }
+}
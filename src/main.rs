@@ -2,9 +2,10 @@ mod config;
 mod handler;
 mod instance;
 
-use std::env;
+use std::{env, time::Duration};
 
 use serenity::{prelude::GatewayIntents, Client};
+use tokio::signal::unix::{signal, SignalKind};
 
 use crate::{config::bot, handler::Handler};
 
@@ -19,16 +20,36 @@ async fn main() {
     log::trace!("Generated Config from {}: {:#?}", cfg_path, cfg);
 
     let client = Client::builder(&cfg.bot_token, GatewayIntents::empty());
+    let shutdown_timeout = Duration::from_secs(cfg.shutdown_timeout);
     let handler = Handler::new(cfg);
     // todo: move thread spawn here if possible
 
     // Build our client.
     let mut client = client
-        .event_handler_arc(handler)
+        .event_handler_arc(handler.clone())
         .await
         .expect("Error creating client");
 
+    let shard_manager = client.shard_manager.clone();
+    tokio::spawn(async move {
+        wait_for_shutdown_signal().await;
+        Handler::shutdown(handler, shutdown_timeout).await;
+        shard_manager.lock().await.shutdown_all().await;
+    });
+
     if let Err(why) = client.start().await {
         log::error!("Client error: {:?}", why);
     }
 }
+
+async fn wait_for_shutdown_signal() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Couldn't listen for SIGTERM");
+
+    tokio::select! {
+        _ = sigterm.recv() => log::info!("Received SIGTERM"),
+        result = tokio::signal::ctrl_c() => match result {
+            Ok(()) => log::info!("Received SIGINT"),
+            Err(err) => log::error!("Couldn't listen for SIGINT: {err}"),
+        },
+    }
+}